@@ -0,0 +1,179 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use nix::libc;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use nix::unistd::Pid;
+use tokio::process::Command;
+
+use crate::structures::ResourceLimits;
+
+// Leaf cgroup the server moves itself into, processes can't live next to child cgroups with controllers
+const SERVER_GROUP: &str = "game_archive-server";
+
+// Period of cpu.max in microseconds
+const CPU_PERIOD: u64 = 100000;
+
+// Directory of the cgroup v2 the server currently is in
+fn current_cgroup() -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    let root = mounts.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.get(2) == Some(&"cgroup2"))?
+        .get(1)?
+        .to_string();
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(&root).join(path.trim_start_matches('/')))
+}
+
+// Directory of the server's cgroup (the parent of SERVER_GROUP once the server moved there)
+fn own_cgroup() -> Option<PathBuf> {
+    let dir = current_cgroup()?;
+    if dir.ends_with(SERVER_GROUP) {
+        return Some(dir.parent()?.to_path_buf());
+    }
+    Some(dir)
+}
+
+fn write_value(dir: &Path, file: &str, value: &str) -> Result<(), String> {
+    fs::write(dir.join(file), value).map_err(|err| format!("{}: {}", dir.join(file).display(), err))
+}
+
+// Name of a session's cgroup, the session is known by its subgame and start
+pub fn cgroup_name(subgame: i64, timestamp_start: i64) -> String {
+    format!("game_archive-{}-{}", subgame, timestamp_start)
+}
+
+// Creates a cgroup with the memory, CPU and cpuset limits. Only works if the server's cgroup
+// is delegated to the user, e.g. when it runs in a systemd user service with Delegate=yes.
+fn create_cgroup(name: &str, limits: &ResourceLimits) -> Result<PathBuf, String> {
+    let base = own_cgroup().ok_or("no cgroup v2 hierarchy")?;
+    let available = fs::read_to_string(base.join("cgroup.controllers")).unwrap_or_default();
+    let mut controllers = vec![];
+    if limits.memory_max.is_some() {
+        controllers.push("memory");
+    }
+    if limits.cpu_weight.is_some() || limits.cpu_quota.is_some() {
+        controllers.push("cpu");
+    }
+    if !limits.cpu_affinity.is_empty() {
+        controllers.push("cpuset");
+    }
+    if let Some(missing) = controllers.iter().find(|controller| !available.split_whitespace().any(|c| c == **controller)) {
+        return Err(format!("the {} controller is not available", missing));
+    }
+    if limits.cpu_quota == Some(0) {
+        return Err("a CPU quota of 0 would never let the game run".to_string());
+    }
+    // The directory is left over from an earlier run after a restart, so it's the membership that counts
+    if !current_cgroup().is_some_and(|dir| dir.ends_with(SERVER_GROUP)) {
+        let server = base.join(SERVER_GROUP);
+        if !server.exists() {
+            fs::create_dir(&server).map_err(|err| err.to_string())?;
+        }
+        write_value(&server, "cgroup.procs", &std::process::id().to_string())?;
+    }
+
+    let enable: Vec<String> = controllers.iter().map(|controller| format!("+{}", controller)).collect();
+    write_value(&base, "cgroup.subtree_control", &enable.join(" "))?;
+
+    let dir = base.join(name);
+    fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+    let result = (|| {
+        if let Some(memory_max) = limits.memory_max {
+            write_value(&dir, "memory.max", &memory_max.to_string())?;
+            // Out of memory the whole game is killed, not some random child of Wine
+            write_value(&dir, "memory.oom.group", "1")?;
+        }
+        if let Some(weight) = limits.cpu_weight {
+            write_value(&dir, "cpu.weight", &weight.clamp(1, 10000).to_string())?;
+        }
+        if let Some(quota) = limits.cpu_quota {
+            write_value(&dir, "cpu.max", &format!("{} {}", quota * CPU_PERIOD / 100, CPU_PERIOD))?;
+        }
+        if !limits.cpu_affinity.is_empty() {
+            let cpus: Vec<String> = limits.cpu_affinity.iter().map(|cpu| cpu.to_string()).collect();
+            write_value(&dir, "cpuset.cpus", &cpus.join(","))?;
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_dir(&dir);
+        return Err(err);
+    }
+    Ok(dir)
+}
+
+// Removes the cgroup of a session once the game is gone, fails silently while processes are left
+pub fn remove_cgroup(name: &str) {
+    if let Some(base) = own_cgroup() {
+        let _ = fs::remove_dir(base.join(name));
+    }
+}
+
+// Sets up the command to start in its own cgroup, or falls back to rlimits and the CPU affinity.
+// The nice level and the open files limit are set either way.
+pub fn apply_limits(command: &mut Command, limits: &ResourceLimits, name: &str) {
+    let needs_cgroup = limits.memory_max.is_some() || limits.cpu_weight.is_some()
+        || limits.cpu_quota.is_some() || !limits.cpu_affinity.is_empty();
+    if !needs_cgroup && limits.nice.is_none() && limits.open_files.is_none() {
+        return;
+    }
+    let procs: Option<File> = if needs_cgroup {
+        match create_cgroup(name, limits).and_then(|dir| {
+            OpenOptions::new().write(true).open(dir.join("cgroup.procs")).map_err(|err| err.to_string())
+        }) {
+            Ok(procs) => {
+                println!("Starting game in cgroup {}", name);
+                Some(procs)
+            },
+            Err(err) => {
+                println!("Unable to create a cgroup ({}), falling back to rlimits", err);
+                if limits.cpu_weight.is_some() || limits.cpu_quota.is_some() {
+                    println!("CPU weight and quota are only applied in a cgroup!");
+                }
+                None
+            },
+        }
+    } else {
+        None
+    };
+
+    let memory_max = limits.memory_max;
+    let cpu_affinity = limits.cpu_affinity.clone();
+    let nice = limits.nice;
+    let open_files = limits.open_files;
+    // Runs in the forked child right before exec, so no allocations in here
+    let setup = move || {
+        // Writing 0 moves the writing process, if that fails the rlimits and affinity still apply
+        let moved = procs.as_ref().is_some_and(|procs| (&*procs).write_all(b"0").is_ok());
+        if !moved {
+            if let Some(memory_max) = memory_max {
+                let _ = setrlimit(Resource::RLIMIT_DATA, memory_max, memory_max);
+            }
+            if !cpu_affinity.is_empty() {
+                let mut cpu_set = CpuSet::new();
+                for cpu in &cpu_affinity {
+                    let _ = cpu_set.set(*cpu);
+                }
+                let _ = sched_setaffinity(Pid::from_raw(0), &cpu_set);
+            }
+        }
+        if let Some(nice) = nice {
+            // Lowering the nice level needs CAP_SYS_NICE, the game still starts without it
+            unsafe {
+                libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+            }
+        }
+        if let Some(open_files) = open_files
+            && let Ok((_, hard)) = getrlimit(Resource::RLIMIT_NOFILE) {
+            let _ = setrlimit(Resource::RLIMIT_NOFILE, open_files.min(hard), hard);
+        }
+        Ok(())
+    };
+    unsafe {
+        command.pre_exec(setup);
+    }
+}
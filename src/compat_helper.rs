@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use dirs::{data_dir, home_dir};
+
+use crate::structures::{CompatTool, CompatToolCandidate, GameConfig};
+
+// Kinds of compat tools, `CompatTool::kind`
+pub const PROTON: &str = "proton";
+pub const UMU: &str = "umu";
+pub const WINE: &str = "wine";
+
+// GAMEID of games that are not in the umu-database, no protonfixes are applied then
+const DEFAULT_GAME_ID: &str = "umu-default";
+
+// Install directory of the Steam client, Proton loads Steam's libraries and legacy runtime from there
+pub fn steam_client_path() -> Option<PathBuf> {
+    let home = home_dir()?;
+    let candidates = [
+        home.join(".steam/root"),
+        home.join(".steam/steam"),
+        data_dir().unwrap_or(home.join(".local/share")).join("Steam"),
+        home.join(".var/app/com.valvesoftware.Steam/data/Steam"),
+    ];
+    candidates.into_iter()
+        .find(|path| path.join("steamapps").is_dir())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+// Wine prefix of a game. Proton keeps it in the "pfx" directory of the compat data,
+// umu points both at the same directory and plain Wine has no compat data.
+pub fn wine_prefix(compat_tool: &CompatTool, game_config: &GameConfig) -> PathBuf {
+    let prefix = PathBuf::from(&game_config.game_prefix);
+    if compat_tool.kind == UMU || compat_tool.kind == WINE {
+        prefix
+    } else {
+        prefix.join("pfx")
+    }
+}
+
+// Arguments of the compat tool's executable that start the game
+pub fn compat_arguments(compat_tool: &CompatTool, game_config: &GameConfig) -> Vec<String> {
+    let mut arguments = vec![];
+    if compat_tool.kind == PROTON {
+        arguments.push("run".to_string());
+    }
+    arguments.push(game_config.executable.clone());
+    arguments.extend(game_config.arguments.iter().cloned());
+    arguments
+}
+
+// Variables the compat tool needs, the environment of the tool and the game are applied after these
+pub fn compat_environment(compat_tool: &CompatTool, game_config: &GameConfig) -> Vec<(String, String)> {
+    let prefix = wine_prefix(compat_tool, game_config).to_string_lossy().to_string();
+    let game_id = if game_config.game_id.is_empty() {
+        DEFAULT_GAME_ID.to_string()
+    } else {
+        game_config.game_id.clone()
+    };
+    let mut environment = vec![("WINEPREFIX".to_string(), prefix)];
+    if !game_config.store.is_empty() {
+        environment.push(("STORE".to_string(), game_config.store.clone()));
+    }
+
+    if compat_tool.kind == WINE {
+        return environment;
+    }
+    if compat_tool.kind == UMU {
+        // umu-run finds the Steam runtime itself and takes PROTONPATH from the tool's environment
+        // (set from `CompatTool::proton_tool`), without one it uses the latest UMU-Proton
+        environment.push(("GAMEID".to_string(), game_id));
+        return environment;
+    }
+
+    environment.push(("STEAM_COMPAT_DATA_PATH".to_string(), game_config.game_prefix.clone()));
+    // Only exported when Steam is found, Proton would take an empty value as a path
+    match steam_client_path() {
+        Some(path) => environment.push(("STEAM_COMPAT_CLIENT_INSTALL_PATH".to_string(), path.to_string_lossy().to_string())),
+        None => println!("Steam client not found, Proton may fail without it. Use an umu compat tool to run without Steam!"),
+    }
+    // Proton-GE's protonfixes look the game up by UMU_ID, Proton's own fixes by the Steam app id
+    environment.push(("UMU_ID".to_string(), game_id.clone()));
+    if let Some(app_id) = game_id.strip_prefix("umu-").filter(|id| id.chars().all(|c| c.is_ascii_digit())) {
+        environment.push(("SteamAppId".to_string(), app_id.to_string()));
+        environment.push(("SteamGameId".to_string(), app_id.to_string()));
+    }
+    environment
+}
+
+// First `name` in $PATH
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = env::var("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|executable| executable.is_file())
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir).into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+// Steam libraries from libraryfolders.vdf, the client's own directory is always one
+fn steam_libraries(steam: &Path) -> Vec<PathBuf> {
+    let mut libraries = vec![steam.to_path_buf()];
+    let folders = fs::read_to_string(steam.join("steamapps/libraryfolders.vdf")).unwrap_or_default();
+    for line in folders.lines() {
+        if let Some(path) = line.trim().strip_prefix("\"path\"") {
+            libraries.push(PathBuf::from(path.trim().trim_matches('"')));
+        }
+    }
+    libraries
+}
+
+// The "version" file of Proton builds reads "<build timestamp> <version>"
+fn proton_version(dir: &Path) -> Option<String> {
+    let version = fs::read_to_string(dir.join("version")).ok()?;
+    let version = version.trim();
+    Some(version.split_once(' ').map(|(_, name)| name).unwrap_or(version).to_string())
+}
+
+fn wine_version(wine: &Path) -> Option<String> {
+    let output = Command::new(wine).arg("--version").output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|version| !version.is_empty())
+}
+
+fn candidate(name: String, executable: &Path, kind: &str, version: Option<String>) -> CompatToolCandidate {
+    CompatToolCandidate {
+        id: None,
+        name,
+        executable: executable.to_string_lossy().to_string(),
+        kind: kind.to_string(),
+        version,
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+// Proton builds in compatibilitytools.d and Steam libraries, Wine from the system and Lutris, and umu-run
+pub fn discover_compat_tools() -> Vec<CompatToolCandidate> {
+    let mut candidates = vec![];
+    let home = home_dir().unwrap_or_default();
+
+    let mut proton_dirs = vec![];
+    for tools in [home.join(".steam/root/compatibilitytools.d"), PathBuf::from("/usr/share/steam/compatibilitytools.d")] {
+        proton_dirs.extend(subdirectories(&tools));
+    }
+    if let Some(steam) = steam_client_path() {
+        for library in steam_libraries(&steam) {
+            proton_dirs.extend(subdirectories(&library.join("steamapps/common"))
+                .into_iter()
+                .filter(|dir| file_name(dir).starts_with("Proton")));
+        }
+    }
+    for dir in proton_dirs {
+        let proton = dir.join("proton");
+        if proton.is_file() {
+            candidates.push(candidate(file_name(&dir), &proton, PROTON, proton_version(&dir)));
+        }
+    }
+
+    let mut wines = vec![];
+    wines.extend(find_executable("wine"));
+    wines.extend(subdirectories(Path::new("/opt"))
+        .into_iter()
+        .filter(|dir| file_name(dir).starts_with("wine"))
+        .map(|dir| dir.join("bin/wine")));
+    for wine in wines.into_iter().filter(|wine| wine.is_file()) {
+        let version = wine_version(&wine);
+        let name = version.clone().unwrap_or_else(|| "Wine".to_string());
+        candidates.push(candidate(name, &wine, WINE, version));
+    }
+    let lutris = data_dir().unwrap_or(home.join(".local/share")).join("lutris/runners/wine");
+    for dir in subdirectories(&lutris) {
+        let wine = dir.join("bin/wine");
+        if wine.is_file() {
+            candidates.push(candidate(format!("Lutris {}", file_name(&dir)), &wine, WINE, Some(file_name(&dir))));
+        }
+    }
+
+    if let Some(umu) = find_executable("umu-run") {
+        candidates.push(candidate("umu-launcher".to_string(), &umu, UMU, None));
+    }
+
+    // ~/.steam/root and ~/.steam/steam usually are the same directory
+    let mut seen = HashSet::new();
+    candidates.retain(|candidate| {
+        seen.insert(fs::canonicalize(&candidate.executable).unwrap_or(PathBuf::from(&candidate.executable)))
+    });
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use rocket::serde::json;
+
+    fn tool(kind: &str) -> CompatTool {
+        CompatTool {
+            id: 1,
+            name: kind.to_string(),
+            executable: format!("/opt/{}/run", kind),
+            environment: HashMap::new(),
+            kind: kind.to_string(),
+            proton_tool: None,
+        }
+    }
+
+    fn config(game_id: &str, store: &str) -> GameConfig {
+        json::serde_json::from_value(json::serde_json::json!({
+            "arguments": ["-windowed"],
+            "working_directory": "",
+            "game_prefix": "/prefixes/1",
+            "executable": "Game.exe",
+            "environment": {},
+            "archive_file": "",
+            "game_id": game_id,
+            "store": store
+        })).unwrap()
+    }
+
+    fn variables(kind: &str, game_config: &GameConfig) -> HashMap<String, String> {
+        compat_environment(&tool(kind), game_config).into_iter().collect()
+    }
+
+    #[test]
+    fn proton_gets_the_steam_variables() {
+        let environment = variables(PROTON, &config("umu-1245620", ""));
+        assert_eq!(environment["WINEPREFIX"], "/prefixes/1/pfx");
+        assert_eq!(environment["STEAM_COMPAT_DATA_PATH"], "/prefixes/1");
+        assert_eq!(environment["UMU_ID"], "umu-1245620");
+        assert_eq!(environment["SteamAppId"], "1245620");
+        assert_eq!(environment["SteamGameId"], "1245620");
+        // Depends on the system, but is never exported empty
+        assert!(environment.get("STEAM_COMPAT_CLIENT_INSTALL_PATH").is_none_or(|path| !path.is_empty()));
+        assert!(!environment.contains_key("STORE"));
+    }
+
+    #[test]
+    fn app_id_only_for_steam_ids() {
+        let environment = variables(PROTON, &config("umu-dishonored", "gog"));
+        assert_eq!(environment["UMU_ID"], "umu-dishonored");
+        assert_eq!(environment["STORE"], "gog");
+        assert!(!environment.contains_key("SteamAppId"));
+
+        let environment = variables(PROTON, &config("", ""));
+        assert_eq!(environment["UMU_ID"], DEFAULT_GAME_ID);
+        assert!(!environment.contains_key("SteamAppId"));
+    }
+
+    #[test]
+    fn umu_and_wine_use_the_prefix_itself() {
+        let environment = variables(UMU, &config("", ""));
+        assert_eq!(environment["WINEPREFIX"], "/prefixes/1");
+        assert_eq!(environment["GAMEID"], DEFAULT_GAME_ID);
+        assert!(!environment.contains_key("STEAM_COMPAT_DATA_PATH"));
+
+        let environment = variables(WINE, &config("umu-1245620", ""));
+        assert_eq!(environment.into_keys().collect::<Vec<String>>(), vec!["WINEPREFIX".to_string()]);
+    }
+
+    #[test]
+    fn only_proton_is_started_with_run() {
+        assert_eq!(compat_arguments(&tool(PROTON), &config("", "")), vec!["run", "Game.exe", "-windowed"]);
+        assert_eq!(compat_arguments(&tool(UMU), &config("", "")), vec!["Game.exe", "-windowed"]);
+    }
+}
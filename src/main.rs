@@ -1,5 +1,5 @@
 use std::io::Write;
-use std::sync::{atomic::AtomicBool, atomic::AtomicIsize, Arc, atomic::AtomicU32};
+use std::sync::{atomic::AtomicBool, atomic::AtomicIsize, Arc, atomic::AtomicU32, Mutex};
 use std::process::Command;
 use std::path::Path;
 use std::{env, fs};
@@ -11,15 +11,149 @@ use rocket::fs::FileServer;
 use rocket::fairing::AdHoc;
 use rocket_db_pools::Database;
 use rocket::launch;
+use tokio::sync::broadcast;
 
 mod routes;
 mod structures;
 //mod database_helper;
 mod mount_helper;
+mod time_helper;
+mod session_helper;
+mod process_helper;
+mod resource_helper;
+mod cgroup_helper;
+mod sandbox_helper;
+mod compat_helper;
+mod prefix_helper;
 
 use structures::*;
 
-use crate::mount_helper::mount_overlayfs;
+use crate::mount_helper::{mount_overlayfs, unmount_all};
+use crate::process_helper::watch_processes;
+use crate::session_helper::{finalize_sessions, recover_sessions};
+use crate::routes::history::apply_failed_launch_threshold;
+
+// Changes to the schema above, applied once and in order (tracked through `PRAGMA user_version`).
+// Never edit an entry that has been released, append a new one instead!
+const MIGRATIONS: &[&str] = &[
+    // History rows have always been written with the subgame id, but `history.game` pointed at `games`.
+    // Rows of subgames that no longer exist can't be re-attributed and are dropped.
+    r#"CREATE TABLE history_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp_start INT NOT NULL,
+            timestamp_end INT NOT NULL,
+            subgame INT NOT NULL,
+            FOREIGN KEY('subgame')
+                REFERENCES 'subgames'('id')
+                ON UPDATE CASCADE
+                ON DELETE CASCADE
+        );
+        INSERT INTO history_new (id, timestamp_start, timestamp_end, subgame)
+            SELECT id, timestamp_start, timestamp_end, game FROM history
+            WHERE game IN (SELECT id FROM subgames);
+        DROP TABLE history;
+        ALTER TABLE history_new RENAME TO history;
+        CREATE INDEX IF NOT EXISTS history_subgame ON history(subgame, timestamp_start);"#,
+    // Every launch is its own history row from now on, merging only happens when displaying sessions.
+    r#"ALTER TABLE history ADD COLUMN exit_code INT;
+        ALTER TABLE history ADD COLUMN launch_profile TEXT;"#,
+    // `subgames.playtime` is recomputed from history from now on. Playtime that history doesn't know about
+    // (typed in by hand or played before history existed) is kept as a manual session before the first one.
+    r#"ALTER TABLE history ADD COLUMN manual BOOL NOT NULL DEFAULT false;
+        INSERT INTO history (subgame, timestamp_start, timestamp_end, manual)
+            SELECT id, anchor - missing, anchor, true FROM (
+                SELECT s.id,
+                       CAST(ROUND(s.playtime * 3600 - COALESCE(SUM(h.timestamp_end - h.timestamp_start), 0)) AS INT) AS missing,
+                       COALESCE(MIN(h.timestamp_start), s.last_launch, CAST(strftime('%s', 'now') AS INT)) AS anchor
+                FROM subgames s
+                LEFT JOIN history h ON h.subgame = s.id
+                GROUP BY s.id
+            ) WHERE missing > 0;
+        UPDATE subgames SET playtime = (
+            SELECT SUM(timestamp_end - timestamp_start) / 3600.0 FROM history WHERE subgame = subgames.id
+        );"#,
+    // Playtime is counted in whole seconds instead of fractional hours
+    r#"ALTER TABLE subgames ADD COLUMN playtime_seconds INTEGER;
+        UPDATE subgames SET playtime_seconds = CAST(ROUND(playtime * 3600) AS INTEGER);
+        ALTER TABLE subgames DROP COLUMN playtime;
+        ALTER TABLE subgames RENAME COLUMN playtime_seconds TO playtime;"#,
+    // When a subgame was played through, used by the year in review
+    r#"ALTER TABLE subgames ADD COLUMN finished INT;"#,
+    // Playtime limits per day / week, for a subgame, a game or (without either) everything
+    r#"CREATE TABLE limits (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            game INT,
+            subgame INT,
+            period TEXT NOT NULL DEFAULT 'day',
+            seconds INT NOT NULL,
+            terminate BOOL NOT NULL DEFAULT false,
+            FOREIGN KEY('game')
+                REFERENCES 'games'('id')
+                ON UPDATE CASCADE
+                ON DELETE CASCADE,
+            FOREIGN KEY('subgame')
+                REFERENCES 'subgames'('id')
+                ON UPDATE CASCADE
+                ON DELETE CASCADE
+        );"#,
+    // How launches ended. Past sessions are marked as failed launches with the configured threshold on startup.
+    r#"ALTER TABLE history ADD COLUMN signal INT;
+        ALTER TABLE history ADD COLUMN terminated BOOL NOT NULL DEFAULT false;
+        ALTER TABLE history ADD COLUMN failed BOOL NOT NULL DEFAULT false;
+        CREATE VIEW crashes AS
+            SELECT id, subgame, timestamp_start,
+                   failed OR (NOT terminated AND (signal IS NOT NULL OR COALESCE(exit_code, 0) != 0)) AS crashed
+            FROM history
+            WHERE manual = false;"#,
+    // Games launched through /api/launch while they run, so a restart of the server doesn't lose them
+    r#"CREATE TABLE running_sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            subgame INT NOT NULL,
+            pid INT NOT NULL,
+            process_start INT,
+            timestamp_start INT NOT NULL,
+            heartbeat INT NOT NULL,
+            launch_profile TEXT NOT NULL,
+            FOREIGN KEY('subgame')
+                REFERENCES 'subgames'('id')
+                ON UPDATE CASCADE
+                ON DELETE CASCADE
+        );"#,
+    // Resource usage of the game's process tree per session, the downsampled series is kept in `resource_samples`
+    r#"ALTER TABLE history ADD COLUMN peak_cpu REAL;
+        ALTER TABLE history ADD COLUMN average_cpu REAL;
+        ALTER TABLE history ADD COLUMN peak_rss INT;
+        ALTER TABLE history ADD COLUMN average_rss INT;
+        ALTER TABLE history ADD COLUMN peak_threads INT;
+        ALTER TABLE history ADD COLUMN read_bytes INT;
+        ALTER TABLE history ADD COLUMN written_bytes INT;
+        ALTER TABLE running_sessions ADD COLUMN resources TEXT;
+        CREATE TABLE resource_samples (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session INT NOT NULL,
+            timestamp INT NOT NULL,
+            cpu REAL NOT NULL,
+            rss INT NOT NULL,
+            threads INT NOT NULL,
+            read_bytes INT NOT NULL,
+            written_bytes INT NOT NULL,
+            FOREIGN KEY('session')
+                REFERENCES 'history'('id')
+                ON UPDATE CASCADE
+                ON DELETE CASCADE
+        );
+        CREATE INDEX resource_samples_session ON resource_samples(session, timestamp);"#,
+    // How a compat tool is started, all tools so far were Proton builds
+    r#"ALTER TABLE compat_tools ADD COLUMN kind TEXT NOT NULL DEFAULT 'proton';"#,
+    // The Proton build an umu tool runs with
+    r#"ALTER TABLE compat_tools ADD COLUMN proton_tool INT REFERENCES compat_tools(id) ON DELETE SET NULL;"#,
+    // Crash count and whether the last launch failed, shown with every subgame
+    r#"CREATE VIEW subgame_status AS
+            SELECT s.id AS subgame,
+                   (SELECT COUNT(*) FROM crashes c WHERE c.subgame = s.id AND c.crashed) AS crash_count,
+                   COALESCE((SELECT c.crashed FROM crashes c WHERE c.subgame = s.id ORDER BY c.timestamp_start DESC, c.id DESC LIMIT 1), false) AS last_launch_failed
+            FROM subgames s;"#,
+];
 
 async fn run_migrations(pool: &sqlx::SqlitePool) {
     sqlx::query(
@@ -87,6 +221,25 @@ async fn run_migrations(pool: &sqlx::SqlitePool) {
     .execute(pool)
     .await
     .expect("Migration Failed!");
+
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(pool)
+        .await
+        .expect("Unable to read schema version!");
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let mut tx = pool.begin().await.expect("Unable to start migration!");
+        sqlx::query(migration)
+            .execute(&mut *tx)
+            .await
+            .expect("Migration Failed!");
+        sqlx::query(&format!("PRAGMA user_version = {}", index + 1))
+            .execute(&mut *tx)
+            .await
+            .expect("Unable to update schema version!");
+        tx.commit().await.expect("Migration Failed!");
+        println!("Applied database migration {}", index + 1);
+    }
 }
 
 fn open_url(url: &str) {
@@ -104,6 +257,17 @@ fn open_url(url: &str) {
 fn write_defconfig(path: &Path) -> std::io::Result<()> {
     let mut f = File::create_new(path.join("Rocket.toml"))?;
     f.write_all(format!(r#"
+        [default]
+        merge_window = 600
+        # time_zone = "Europe/Berlin"
+        week_start = "Monday"
+        limit_warnings = [900, 300]
+        failed_launch_threshold = 15
+        count_failed_launches = false
+        passive_tracking = false
+        passive_tracking_interval = 10
+        shutdown_action = "detach"
+
         [default.databases.sqlite_db]
         url = "sqlite://{}/games.sqlite"
     "#, path.to_str().unwrap()).as_bytes())?;
@@ -117,6 +281,11 @@ fn rocket() -> _ {
         current_game: AtomicIsize::new(-1),
         running_since: AtomicIsize::new(0),
         pid: AtomicU32::new(0),
+        limit_warning: Mutex::new(None),
+        terminate_requested: AtomicBool::new(false),
+        session_id: AtomicIsize::new(0),
+        events: broadcast::channel(64).0,
+        resources: Mutex::new(None),
     });
    
     // Why no "proper" error handling?
@@ -150,11 +319,59 @@ fn rocket() -> _ {
             run_migrations(db_pool).await;
             rocket
         }))
+        .attach(AdHoc::config::<Settings>())
+        // Playtime limits are looked up in the configured zone, an unknown one must not switch them off
+        .attach(AdHoc::try_on_ignite("Validate Settings", |rocket| async {
+            let settings = rocket.state::<Settings>().unwrap();
+            if let Some(name) = &settings.time_zone && name.parse::<chrono_tz::Tz>().is_err() {
+                println!("ERROR! Unknown time_zone \"{}\" in Rocket.toml", name);
+                return Err(rocket);
+            }
+            Ok(rocket)
+        }))
+        .attach(AdHoc::on_ignite("Apply Failed Launch Threshold", |rocket| async {
+            let settings = rocket.state::<Settings>().unwrap();
+            let db_pool = Db::fetch(&rocket).unwrap();
+            if let Err(err) = apply_failed_launch_threshold(db_pool, settings).await {
+                println!("Unable to mark failed launches: {}", err);
+            }
+            rocket
+        }))
+        .attach(AdHoc::on_liftoff("Recover Sessions", |rocket| Box::pin(async move {
+            let settings = rocket.state::<Settings>().unwrap().clone();
+            let db_pool = (**Db::fetch(rocket).unwrap()).clone();
+            let game_runtime = rocket.state::<Arc<GameRuntime>>().unwrap().clone();
+            recover_sessions(db_pool, settings, game_runtime, rocket.shutdown()).await;
+        })))
+        .attach(AdHoc::on_shutdown("Finalize Sessions", |rocket| Box::pin(async move {
+            let settings = rocket.state::<Settings>().unwrap();
+            let game_runtime = rocket.state::<Arc<GameRuntime>>().unwrap();
+            // Games that were let go still run from the archive's mounts
+            if finalize_sessions(Db::fetch(rocket).unwrap(), settings, game_runtime).await {
+                println!("Games are still running, leaving the archive mounted");
+            } else {
+                unmount_all();
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Passive Tracking", |rocket| Box::pin(async move {
+            let settings = rocket.state::<Settings>().unwrap().clone();
+            if settings.passive_tracking {
+                let db_pool = (**Db::fetch(rocket).unwrap()).clone();
+                let game_runtime = rocket.state::<Arc<GameRuntime>>().unwrap().clone();
+                tokio::spawn(watch_processes(db_pool, settings, game_runtime));
+            }
+        })))
         .manage(runtime)
         .mount("/api", routes::game::routes())
         .mount("/api", routes::media::routes())
         .mount("/api", routes::backend_launch::routes())
         .mount("/api", routes::game_config::routes())
+        .mount("/api", routes::history::routes())
+        .mount("/api", routes::stats::routes())
+        .mount("/api", routes::review::routes())
+        .mount("/api", routes::export::routes())
+        .mount("/api", routes::limits::routes())
+        .mount("/api", routes::prefix::routes())
         .mount("/", FileServer::from(config_dir.join("web-mixin")).rank(10))
         .mount("/", routes::embedded_files::routes())
 }
@@ -1,6 +1,7 @@
 use std::path::Path;
 use damascus::{Filesystem, FuseOverlayFs, StateRecovery};
 use std::fs;
+use std::process::Command;
 use dirs::config_dir;
 
 pub fn mount_overlayfs() {
@@ -23,4 +24,30 @@ pub fn mount_overlayfs() {
     let mut o = FuseOverlayFs::writable([&lower_ro], upper, work, overlay).unwrap();
     o.set_scoped(false);
     o.mount().unwrap();
-} 
+}
+
+// Mount points below the archive's mount directory, in the order they were mounted
+pub fn archive_mounts() -> Vec<String> {
+    let mount_dir = config_dir().expect("Error locating config dir!").join("game_archive/mount");
+    let Ok(mounts) = fs::read_to_string("/proc/self/mounts") else {
+        return vec![];
+    };
+    mounts.lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(|point| point.replace("\\040", " "))
+        .filter(|point| Path::new(point).starts_with(&mount_dir))
+        .collect()
+}
+
+// Unmounts the overlay and the SquashFS images below it, the last mounted first
+pub fn unmount_all() {
+    for point in archive_mounts().into_iter().rev() {
+        // FUSE mounts of a normal user can only be removed through fusermount
+        let status = Command::new("fusermount3").args(["-u", &point]).status()
+            .or_else(|_| Command::new("fusermount").args(["-u", &point]).status());
+        match status {
+            Ok(status) if status.success() => println!("Unmounted {}", point),
+            _ => println!("Unable to unmount {}!", point),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use dirs::config_dir;
+use tokio::process::Command;
+
+use crate::compat_helper::{compat_arguments, compat_environment, find_executable, wine_prefix, PROTON, UMU, WINE};
+use crate::structures::{CompatTool, GameConfig};
+
+pub fn prefixes_dir() -> PathBuf {
+    config_dir().expect("Error locating config dir!").join("game_archive/prefixes")
+}
+
+// Templates are prefixes of any kind that are copied for new subgames
+pub fn templates_dir() -> PathBuf {
+    prefixes_dir().join("templates")
+}
+
+// Managed prefix of a subgame
+pub fn subgame_prefix(subgame: i64) -> PathBuf {
+    prefixes_dir().join(subgame.to_string())
+}
+
+// Only the managed prefix of the subgame itself is ever deleted, never the prefixes directory, a template
+// or a prefix set up by hand
+pub fn is_managed(path: &Path, subgame: i64) -> bool {
+    same_directory(path, &subgame_prefix(subgame))
+}
+
+// Both exist and are the same directory once symlinks and ".." are resolved
+fn same_directory(path: &Path, other: &Path) -> bool {
+    path.canonicalize().is_ok_and(|path| other.canonicalize().is_ok_and(|other| path == other))
+}
+
+// Copies a prefix on a blocking thread, prefixes easily are a few GB
+pub async fn copy_prefix(from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    tokio::task::spawn_blocking(move || copy_dir(&from, &to)).await.unwrap_or_else(|err| Err(io::Error::other(err)))
+}
+
+// Symlinks (dosdevices, the user folders, Proton's files) are copied as they are
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, target)?;
+        } else if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+// Wine binary of a compat tool, None for umu which brings its own
+fn wine_binary(compat_tool: &CompatTool) -> Option<PathBuf> {
+    let executable = PathBuf::from(&compat_tool.executable);
+    match compat_tool.kind.as_str() {
+        WINE => Some(executable),
+        // Proton 5 and later keep Wine in "files", older ones in "dist"
+        PROTON => ["files/bin/wine", "dist/bin/wine"].iter()
+            .map(|wine| executable.parent().unwrap_or(Path::new("/")).join(wine))
+            .find(|wine| wine.is_file()),
+        _ => None,
+    }
+}
+
+async fn run(mut command: Command, what: &str) -> bool {
+    match command.output().await {
+        Ok(output) => {
+            println!("{} finished with {}", what, output.status);
+            if !output.status.success() {
+                println!("{}", String::from_utf8_lossy(&output.stderr));
+            }
+            output.status.success()
+        },
+        Err(err) => {
+            println!("Unable to run {}: {}", what, err);
+            false
+        },
+    }
+}
+
+// Runs a Windows program in the subgame's prefix through its compat tool, like the game itself is started
+pub async fn run_in_prefix(compat_tool: &CompatTool, game_config: &GameConfig, executable: &str, arguments: &[&str]) -> bool {
+    let mut config = game_config.clone();
+    config.executable = executable.to_string();
+    config.arguments = arguments.iter().map(|argument| argument.to_string()).collect();
+    let mut command = Command::new(&compat_tool.executable);
+    command.current_dir(&game_config.game_prefix)
+        .args(compat_arguments(compat_tool, &config))
+        .envs(compat_environment(compat_tool, &config))
+        .envs(compat_tool.environment.clone())
+        .envs(game_config.environment.clone());
+    run(command, executable).await
+}
+
+// Creates or updates the prefix, Proton sets up a new one on its first start
+pub async fn wineboot(compat_tool: &CompatTool, game_config: &GameConfig) -> bool {
+    run_in_prefix(compat_tool, game_config, "wineboot", &["-u"]).await
+}
+
+// umu-run has winetricks built in, otherwise winetricks from $PATH is pointed at the tool's Wine
+pub async fn winetricks(compat_tool: &CompatTool, game_config: &GameConfig, verbs: &[String]) -> bool {
+    let mut arguments = vec!["-q"];
+    arguments.extend(verbs.iter().map(String::as_str));
+    if compat_tool.kind == UMU {
+        return run_in_prefix(compat_tool, game_config, "winetricks", &arguments).await;
+    }
+    let (Some(winetricks), Some(wine)) = (find_executable("winetricks"), wine_binary(compat_tool)) else {
+        println!("winetricks or the Wine of {} was not found!", compat_tool.name);
+        return false;
+    };
+    let mut command = Command::new(winetricks);
+    command.current_dir(&game_config.game_prefix)
+        .args(arguments)
+        .envs(compat_tool.environment.clone())
+        .env("WINEPREFIX", wine_prefix(compat_tool, game_config))
+        .env("WINESERVER", wine.with_file_name("wineserver"))
+        .env("WINE", wine);
+    run(command, "winetricks").await
+}
+
+// Imports a .reg file, it is placed on C: for the time of the import
+pub async fn import_registry(compat_tool: &CompatTool, game_config: &GameConfig, registry: &[u8]) -> bool {
+    let drive_c = wine_prefix(compat_tool, game_config).join("drive_c");
+    let file = drive_c.join("game_archive_import.reg");
+    if let Err(err) = fs::create_dir_all(&drive_c).and_then(|_| fs::write(&file, registry)) {
+        println!("Unable to write {}: {}", file.display(), err);
+        return false;
+    }
+    let imported = run_in_prefix(compat_tool, game_config, "regedit", &["/S", "C:\\game_archive_import.reg"]).await;
+    let _ = fs::remove_file(file);
+    imported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_prefix_itself_is_the_same_directory() {
+        let dir = std::env::temp_dir().join(format!("game_archive-prefixes-{}", std::process::id()));
+        let prefix = dir.join("1");
+        fs::create_dir_all(prefix.join("pfx")).unwrap();
+        fs::create_dir_all(dir.join("templates/base")).unwrap();
+        symlink(&prefix, dir.join("link")).unwrap();
+
+        assert!(same_directory(&prefix, &prefix));
+        assert!(same_directory(&dir.join("link"), &prefix));
+        assert!(same_directory(&prefix.join("pfx/.."), &prefix));
+        assert!(!same_directory(&dir, &prefix));
+        assert!(!same_directory(&prefix.join("pfx"), &prefix));
+        assert!(!same_directory(&dir.join("templates/base"), &prefix));
+        assert!(!same_directory(&dir.join("2"), &dir.join("2")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
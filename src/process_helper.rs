@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use chrono::Utc;
+use rocket::serde::json;
+use rocket_db_pools::sqlx::{self, SqlitePool, SqliteConnection};
+
+use crate::routes::history::update_playtime;
+use crate::structures::{GameConfig, GameRuntime, Settings};
+
+// Launch profile of sessions recorded by the watcher
+pub const PASSIVE_PROFILE: &str = "passive";
+
+// The executable and argv[0] of a process, arguments are not looked at so a file or game merely passed
+// to another program doesn't count. Windows paths of Wine / Proton games ("Z:\home\...\game.exe") are
+// turned into Unix ones.
+fn process_paths(pid: &str) -> Vec<String> {
+    let mut paths = vec![];
+    if let Ok(exe) = fs::read_link(format!("/proc/{}/exe", pid)) {
+        paths.push(exe.to_string_lossy().to_string());
+    }
+    if let Ok(cmdline) = fs::read(format!("/proc/{}/cmdline", pid))
+        && let Some(arg) = cmdline.split(|b| *b == 0).next().filter(|arg| !arg.is_empty()) {
+        paths.push(unix_path(&String::from_utf8_lossy(arg)));
+    }
+    paths
+}
+
+fn unix_path(arg: &str) -> String {
+    if !arg.to_lowercase().ends_with(".exe") {
+        return arg.to_string();
+    }
+    let arg = arg.replace('\\', "/");
+    arg.strip_prefix("Z:").or(arg.strip_prefix("z:")).unwrap_or(&arg).to_string()
+}
+
+// Start time of a process in clock ticks after boot, None once it is gone
+pub fn process_start_time(pid: u32) -> Option<i64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name in parentheses may contain spaces, the start time is the 20th field after it
+    stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn file_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_lowercase()
+}
+
+// Executables given as a path (absolute or relative to the working directory) have to match exactly,
+// a bare file name matches wherever the game was installed
+fn matches(paths: &[String], config: &GameConfig) -> bool {
+    if config.executable.is_empty() {
+        return false;
+    }
+    if !config.executable.contains('/') && config.working_directory.is_empty() {
+        let name = config.executable.to_lowercase();
+        return paths.iter().any(|path| file_name(path) == name);
+    }
+    let full = Path::new(&config.working_directory).join(&config.executable);
+    paths.iter().any(|path| Path::new(path) == full)
+}
+
+fn running_subgames(configs: &[(i64, GameConfig)]) -> HashSet<i64> {
+    let mut running = HashSet::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return running;
+    };
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().to_string();
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let paths = process_paths(&pid);
+        for (subgame, config) in configs {
+            if matches(&paths, config) {
+                running.insert(*subgame);
+            }
+        }
+    }
+    running
+}
+
+async fn open_session(db: &mut SqliteConnection, subgame: i64, now: i64) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO history (subgame, timestamp_start, timestamp_end, launch_profile) VALUES (?, ?, ?, ?); SELECT last_insert_rowid() AS id;",
+        subgame,
+        now,
+        now,
+        PASSIVE_PROFILE
+    ).fetch_one(&mut *db)
+    .await?;
+    Ok(row.id as i64)
+}
+
+async fn close_session(db: &mut SqliteConnection, settings: &Settings, subgame: i64, session: (i64, i64), now: i64) -> Result<(), sqlx::Error> {
+    let (id, start) = session;
+    let failed = now - start < settings.failed_launch_threshold;
+    sqlx::query!(
+        "UPDATE history SET timestamp_end = ?, failed = ? WHERE id = ?",
+        now,
+        failed,
+        id
+    ).execute(&mut *db)
+    .await?;
+    sqlx::query!(
+        "UPDATE subgames SET last_launch = ? WHERE id = ?",
+        start,
+        subgame
+    ).execute(&mut *db)
+    .await?;
+    update_playtime(db, settings, subgame).await
+}
+
+// Scans /proc for the executables of all subgames and records a session for as long as one of them runs.
+// Open sessions are extended on every scan, so they stay accurate if the server goes away.
+// Games launched through /api/launch are recorded by the launcher instead.
+pub async fn watch_processes(db_pool: SqlitePool, settings: Settings, game_runtime: Arc<GameRuntime>) {
+    println!("Watching for games started outside the archive");
+    let mut open: HashMap<i64, (i64, i64)> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.passive_tracking_interval.max(1)));
+    loop {
+        interval.tick().await;
+        let Ok(mut db) = db_pool.acquire().await else {
+            continue;
+        };
+        let Ok(rows) = sqlx::query!("SELECT id, launch_config FROM subgames").fetch_all(&mut *db).await else {
+            continue;
+        };
+        let configs: Vec<(i64, GameConfig)> = rows.into_iter()
+            .filter_map(|row| Some((row.id, json::serde_json::from_str::<GameConfig>(&row.launch_config).ok()?)))
+            .collect();
+        let launched = game_runtime.game_running.load(Ordering::SeqCst)
+            .then(|| game_runtime.current_game.load(Ordering::Relaxed) as i64);
+        let running = running_subgames(&configs);
+        let now = Utc::now().timestamp();
+
+        for subgame in &running {
+            if let Some((id, _)) = open.get(subgame) {
+                if let Err(err) = sqlx::query!("UPDATE history SET timestamp_end = ? WHERE id = ?", now, id).execute(&mut *db).await {
+                    println!("ERROR! {}", err);
+                }
+            } else if launched != Some(*subgame) {
+                match open_session(&mut db, *subgame, now).await {
+                    Ok(id) => {
+                        println!("Subgame {} was started outside the archive, recording it", subgame);
+                        open.insert(*subgame, (id, now));
+                    },
+                    Err(err) => println!("ERROR! {}", err),
+                }
+            }
+        }
+
+        let stopped: Vec<i64> = open.keys().filter(|subgame| !running.contains(subgame)).copied().collect();
+        for subgame in stopped {
+            let session = open.remove(&subgame).unwrap();
+            println!("Subgame {} is no longer running", subgame);
+            if let Err(err) = close_session(&mut db, &settings, subgame, session, now).await {
+                println!("ERROR! {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(executable: &str, working_directory: &str) -> GameConfig {
+        json::serde_json::from_value(json::serde_json::json!({
+            "arguments": [],
+            "working_directory": working_directory,
+            "game_prefix": "",
+            "executable": executable,
+            "environment": {},
+            "archive_file": ""
+        })).unwrap()
+    }
+
+    #[test]
+    fn wine_paths_of_exe_files_are_converted() {
+        assert_eq!(unix_path("Z:\\home\\me\\Game\\Game.exe"), "/home/me/Game/Game.exe");
+        assert_eq!(unix_path("C:\\windows\\system32\\services.exe"), "C:/windows/system32/services.exe");
+        assert_eq!(unix_path("/usr/bin/python3"), "/usr/bin/python3");
+    }
+
+    #[test]
+    fn bare_names_match_anywhere() {
+        let paths = vec!["/usr/bin/wine64-preloader".to_string(), "/home/me/Game/Game.exe".to_string()];
+        assert!(matches(&paths, &config("game.EXE", "")));
+        assert!(!matches(&paths, &config("other.exe", "")));
+        assert!(!matches(&paths, &config("", "")));
+    }
+
+    #[test]
+    fn paths_match_exactly() {
+        let paths = vec!["/home/me/Game/bin/game".to_string()];
+        assert!(matches(&paths, &config("/home/me/Game/bin/game", "")));
+        assert!(matches(&paths, &config("bin/game", "/home/me/Game")));
+        assert!(!matches(&paths, &config("game", "/home/me/Other")));
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::Instant;
+use chrono::Utc;
+use nix::unistd::{sysconf, SysconfVar};
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::structures::{ResourceSample, ResourceUsage};
+
+// Seconds between two samples while a game runs
+pub const SAMPLE_INTERVAL: u64 = 5;
+
+// Points kept per session, older ones are merged pairwise when there are more
+const MAX_SAMPLES: usize = 240;
+
+// CPU time (clock ticks), resident pages, threads, bytes read and written of a single process
+struct ProcessUsage {
+    cpu: i64,
+    rss: i64,
+    threads: i64,
+    read_bytes: i64,
+    written_bytes: i64,
+}
+
+fn stat_fields(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name in parentheses may contain spaces, the fields after it start with the state (3rd field)
+    Some(stat.rsplit_once(')')?.1.split_whitespace().map(str::to_string).collect())
+}
+
+// `root` and every process below it, Proton starts the actual game a few levels down
+fn process_tree(root: u32) -> Vec<u32> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+                continue;
+            };
+            if let Some(parent) = stat_fields(pid).and_then(|fields| fields.get(1)?.parse::<u32>().ok()) {
+                children.entry(parent).or_default().push(pid);
+            }
+        }
+    }
+    let mut tree = vec![root];
+    let mut index = 0;
+    while index < tree.len() {
+        tree.extend(children.get(&tree[index]).into_iter().flatten());
+        index += 1;
+    }
+    tree
+}
+
+fn process_usage(pid: u32) -> Option<ProcessUsage> {
+    let fields = stat_fields(pid)?;
+    let number = |index: usize| fields.get(index).and_then(|field| field.parse::<i64>().ok());
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    // Only readable for processes of the same user, which the game's are
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).unwrap_or_default();
+    let io_value = |key: &str| io.lines()
+        .find_map(|line| line.strip_prefix(key))
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .unwrap_or(0);
+    Some(ProcessUsage {
+        cpu: number(11)? + number(12)?,
+        rss: statm.split_whitespace().nth(1)?.parse().ok()?,
+        threads: number(17)?,
+        read_bytes: io_value("read_bytes:"),
+        written_bytes: io_value("write_bytes:"),
+    })
+}
+
+fn system_value(var: SysconfVar, default: i64) -> i64 {
+    sysconf(var).ok().flatten().unwrap_or(default)
+}
+
+// Samples the process tree of a game and keeps a downsampled series plus peak / average values.
+// It is saved with the running session, so it carries over a restart of the server.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ResourceSampler {
+    samples: Vec<ResourceSample>,
+    // 2^thinned raw samples are merged into each new point of `samples`
+    thinned: u32,
+    pending: Vec<ResourceSample>,
+    usage: ResourceUsage,
+    sample_count: i64,
+    read_bytes: i64,
+    written_bytes: i64,
+    // CPU ticks and I/O bytes per process at the last sample, CPU time of processes that exited is kept that way
+    #[serde(skip)]
+    last: HashMap<u32, (i64, i64, i64)>,
+    #[serde(skip)]
+    last_instant: Option<Instant>,
+}
+
+impl ResourceSampler {
+    // Sampler of a session that was saved with `to_json`
+    pub fn restore(json: Option<&str>) -> Self {
+        json.and_then(|json| rocket::serde::json::serde_json::from_str(json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        rocket::serde::json::serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn sample(&mut self, pid: u32) {
+        let ticks = system_value(SysconfVar::CLK_TCK, 100) as f64;
+        let page_size = system_value(SysconfVar::PAGE_SIZE, 4096);
+        let now = Instant::now();
+        let elapsed = self.last_instant.map(|last| now.duration_since(last).as_secs_f64());
+        self.last_instant = Some(now);
+        // Right after starting or a restore only the counters of the running processes are noted
+        let baseline = elapsed.is_none();
+
+        let mut cpu_ticks = 0;
+        let mut sample = ResourceSample { timestamp: Utc::now().timestamp(), ..Default::default() };
+        let mut seen = HashSet::new();
+        for pid in process_tree(pid) {
+            let Some(usage) = process_usage(pid) else {
+                continue;
+            };
+            let (last_cpu, last_read, last_written) = self.last.get(&pid).copied().unwrap_or_default();
+            if !baseline {
+                cpu_ticks += (usage.cpu - last_cpu).max(0);
+                self.read_bytes += (usage.read_bytes - last_read).max(0);
+                self.written_bytes += (usage.written_bytes - last_written).max(0);
+            }
+            self.last.insert(pid, (usage.cpu, usage.read_bytes, usage.written_bytes));
+            seen.insert(pid);
+            sample.rss += usage.rss * page_size;
+            sample.threads += usage.threads;
+        }
+        self.last.retain(|pid, _| seen.contains(pid));
+        let Some(elapsed) = elapsed.filter(|_| !seen.is_empty()) else {
+            return;
+        };
+        sample.cpu = cpu_ticks as f64 / ticks / elapsed;
+        sample.read_bytes = self.read_bytes;
+        sample.written_bytes = self.written_bytes;
+        self.record(sample);
+    }
+
+    fn record(&mut self, sample: ResourceSample) {
+        let usage = &mut self.usage;
+        let count = self.sample_count as f64;
+        usage.peak_cpu = usage.peak_cpu.max(sample.cpu);
+        usage.average_cpu = (usage.average_cpu * count + sample.cpu) / (count + 1.0);
+        usage.peak_rss = usage.peak_rss.max(sample.rss);
+        usage.average_rss = ((usage.average_rss as f64 * count + sample.rss as f64) / (count + 1.0)) as i64;
+        usage.peak_threads = usage.peak_threads.max(sample.threads);
+        usage.read_bytes = sample.read_bytes;
+        usage.written_bytes = sample.written_bytes;
+        usage.current = Some(sample.clone());
+        self.sample_count += 1;
+
+        self.pending.push(sample);
+        if self.pending.len() >= 1 << self.thinned {
+            let merged = merge(&self.pending);
+            self.pending.clear();
+            self.samples.push(merged);
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples = self.samples.chunks(2).map(merge).collect();
+            self.thinned += 1;
+        }
+    }
+
+    pub fn usage(&self) -> Option<ResourceUsage> {
+        (self.sample_count > 0).then(|| self.usage.clone())
+    }
+
+    pub fn samples(&self) -> Vec<ResourceSample> {
+        let mut samples = self.samples.clone();
+        if !self.pending.is_empty() {
+            samples.push(merge(&self.pending));
+        }
+        samples
+    }
+}
+
+// Average of consecutive samples, counters and the timestamp are taken from the last one
+fn merge(samples: &[ResourceSample]) -> ResourceSample {
+    let count = samples.len().max(1) as f64;
+    let last = samples.last().cloned().unwrap_or_default();
+    ResourceSample {
+        timestamp: last.timestamp,
+        cpu: samples.iter().map(|s| s.cpu).sum::<f64>() / count,
+        rss: (samples.iter().map(|s| s.rss).sum::<i64>() as f64 / count) as i64,
+        threads: (samples.iter().map(|s| s.threads).sum::<i64>() as f64 / count).round() as i64,
+        read_bytes: last.read_bytes,
+        written_bytes: last.written_bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64, cpu: f64, rss: i64) -> ResourceSample {
+        ResourceSample { timestamp, cpu, rss, threads: 4, read_bytes: timestamp * 10, written_bytes: timestamp }
+    }
+
+    #[test]
+    fn record_keeps_peaks_and_averages() {
+        let mut sampler = ResourceSampler::default();
+        assert!(sampler.usage().is_none());
+        sampler.record(sample(1, 1.0, 100));
+        sampler.record(sample(2, 3.0, 300));
+        let usage = sampler.usage().unwrap();
+        assert_eq!((usage.peak_cpu, usage.average_cpu), (3.0, 2.0));
+        assert_eq!((usage.peak_rss, usage.average_rss), (300, 200));
+        // I/O counters are totals already
+        assert_eq!((usage.read_bytes, usage.written_bytes), (20, 2));
+        assert_eq!(usage.current.unwrap().timestamp, 2);
+    }
+
+    #[test]
+    fn merge_averages_and_keeps_the_last_counters() {
+        let merged = merge(&[sample(1, 1.0, 100), sample(2, 2.0, 200)]);
+        assert_eq!((merged.timestamp, merged.cpu, merged.rss, merged.threads), (2, 1.5, 150, 4));
+        assert_eq!((merged.read_bytes, merged.written_bytes), (20, 2));
+    }
+
+    #[test]
+    fn series_is_thinned_out_at_max_samples() {
+        let mut sampler = ResourceSampler::default();
+        for timestamp in 0..MAX_SAMPLES as i64 {
+            sampler.record(sample(timestamp, timestamp as f64, 0));
+        }
+        assert_eq!(sampler.samples().len(), MAX_SAMPLES / 2);
+        assert_eq!(sampler.samples()[0].cpu, 0.5);
+        assert_eq!(sampler.samples()[0].timestamp, 1);
+
+        // From now on two samples make one point, a half finished one is shown as well
+        sampler.record(sample(1000, 0.0, 0));
+        assert_eq!(sampler.samples().len(), MAX_SAMPLES / 2 + 1);
+        sampler.record(sample(1001, 1.0, 0));
+        let samples = sampler.samples();
+        assert_eq!(samples.len(), MAX_SAMPLES / 2 + 1);
+        assert_eq!((samples.last().unwrap().timestamp, samples.last().unwrap().cpu), (1001, 0.5));
+        assert_eq!(sampler.usage().unwrap().peak_cpu, (MAX_SAMPLES - 1) as f64);
+    }
+
+    #[test]
+    fn restored_sampler_continues_the_series() {
+        let mut sampler = ResourceSampler::default();
+        sampler.record(sample(1, 1.0, 100));
+        let mut restored = ResourceSampler::restore(Some(&sampler.to_json()));
+        restored.record(sample(2, 3.0, 300));
+        assert_eq!(restored.samples().len(), 2);
+        assert_eq!(restored.usage().unwrap().average_cpu, 2.0);
+        assert!(ResourceSampler::restore(Some("not json")).usage().is_none());
+    }
+}
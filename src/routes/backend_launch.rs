@@ -1,23 +1,30 @@
-use rocket::{get, routes, State};
+use rocket::{get, routes, Shutdown, State};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json;
 use chrono::Utc;
 use uuid::timestamp;
 use std::thread;
 use std::collections::HashMap;
 use std::sync::{atomic::Ordering, Arc};
-use tokio::process::Command;
+use std::time::Duration;
+use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use tokio::sync::broadcast::error::RecvError;
 use nix::unistd::Pid;
 use nix::sys::{signal, signal::Signal};
 use rocket_db_pools::{Connection, sqlx};
-use rocket_db_pools::sqlx::Acquire;
+use rocket_db_pools::sqlx::{Acquire, SqliteConnection};
 
-use crate::structures::{Db, GameConfig, CompatTool, GameRuntime, GameHistory, HistoryType, HistoryGame};
-
-struct IntermediateTimestamp {
-    timestamp: Option<String>,
-    game: i64,
-    total_playtime: i64
-}
+use crate::routes::history::update_playtime;
+use crate::routes::limits::remaining_playtime;
+use crate::resource_helper::{ResourceSampler, SAMPLE_INTERVAL};
+use crate::session_helper::{heartbeat, persist_session, PlaySegments, HEARTBEAT_INTERVAL};
+use crate::mount_helper::archive_mounts;
+use crate::cgroup_helper::{apply_limits, cgroup_name, remove_cgroup};
+use crate::sandbox_helper::{game_command, isolate_network};
+use crate::compat_helper::{compat_arguments, compat_environment, UMU};
+use crate::structures::{Db, GameConfig, CompatTool, GameRuntime, ResourceSample, RunningSession, RuntimeEvent, RuntimeStatus, Session, Settings};
 
 pub async fn get_game_conf(db: &mut Connection<Db>, id: i64) -> Option<GameConfig> {
     let conn = db.acquire().await.ok()?;
@@ -34,162 +41,310 @@ pub async fn get_game_conf(db: &mut Connection<Db>, id: i64) -> Option<GameConfi
 pub async fn get_compat_tool(db: &mut Connection<Db>, id: i64) -> Option<CompatTool> {
     let conn = db.acquire().await.ok()?;
     let rows = sqlx::query!(
-        "SELECT ct.id, ct.name, ct.executable, ct.environment
+        "SELECT ct.id, ct.name, ct.executable, ct.environment, ct.kind, ct.proton_tool, p.executable AS \"proton_executable?\"
         FROM subgames g
         JOIN compat_tools ct ON g.compat_tool = ct.id
+        LEFT JOIN compat_tools p ON ct.proton_tool = p.id AND p.kind = 'proton'
         WHERE g.id = ?;",
         id
     ).fetch_optional(conn)
     .await
     .ok()??;
 
+    let mut environment = json::serde_json::from_str::<HashMap<String, String>>(&rows.environment?).ok()?;
+    // umu-run wants the Proton directory, a PROTONPATH set by hand wins
+    if rows.kind == UMU && let Some(proton) = rows.proton_executable {
+        let proton_dir = Path::new(&proton).parent().unwrap_or(Path::new("/")).to_string_lossy().to_string();
+        environment.entry("PROTONPATH".to_string()).or_insert(proton_dir);
+    }
     Some(
         CompatTool { 
             id: rows.id,
             name: rows.name,
             executable: rows.executable,
-            environment: json::serde_json::from_str::<HashMap<String, String>>(&rows.environment?).ok()? 
+            environment,
+            kind: rows.kind,
+            proton_tool: rows.proton_tool,
         }
     )
 }
 
-pub async fn add_to_history(db: &mut Connection<Db>, timestamp_start: i64, timestamp_end: i64, game: i64) -> Result<(), Box<dyn std::error::Error>>{
-    // Only insert new row when last game session was more than 600s away (keep the table clean) 
-    let conn = db.acquire().await?;
-    sqlx::query!(
-        r#"
-        INSERT INTO history (id, game, timestamp_start, timestamp_end)
-        VALUES (
-            (
-                SELECT id
-                FROM history
-                WHERE game = ?3
-                  AND (?2 - timestamp_end) <= 600
-                ORDER BY timestamp_end DESC
-                LIMIT 1
-            ),?3,?2,?1
-        )
-        
-        ON CONFLICT(id) DO UPDATE SET
-            timestamp_end = excluded.timestamp_end;
-        "#,
-        timestamp_end,
-        timestamp_start,
-        game
-    ).execute(conn).await?;
-    Ok(())
+// Returns the id of the new history row
+pub async fn add_to_history(db: &mut SqliteConnection, session: &Session) -> Result<i64, sqlx::Error>{
+    let row = sqlx::query!(
+        "INSERT INTO history (subgame, timestamp_start, timestamp_end, exit_code, launch_profile, signal, terminated, failed,
+                              peak_cpu, average_cpu, peak_rss, average_rss, peak_threads, read_bytes, written_bytes)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?); SELECT last_insert_rowid() AS id;",
+        session.subgame,
+        session.timestamp_start,
+        session.timestamp_end,
+        session.exit_code,
+        session.launch_profile,
+        session.signal,
+        session.terminated,
+        session.failed,
+        session.peak_cpu,
+        session.average_cpu,
+        session.peak_rss,
+        session.average_rss,
+        session.peak_threads,
+        session.read_bytes,
+        session.written_bytes
+    ).fetch_one(&mut *db).await?;
+    Ok(row.id as i64)
 }
 
-#[get("/status")]
-async fn get_status(game_runtime: &State<Arc<GameRuntime>>) -> String{
-    let game_runtime: Arc<GameRuntime> = game_runtime.inner().clone();
-    format!("{{\"current_game\": {}, \"game_running\": {}, \"running_since\": {}}}", game_runtime.current_game.load(Ordering::Relaxed), game_runtime.game_running.load(Ordering::Relaxed), game_runtime.running_since.load(Ordering::Relaxed))
+async fn add_resource_samples(db: &mut SqliteConnection, session: i64, samples: &[ResourceSample]) -> Result<(), sqlx::Error> {
+    for sample in samples {
+        sqlx::query!(
+            "INSERT INTO resource_samples (session, timestamp, cpu, rss, threads, read_bytes, written_bytes) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            session,
+            sample.timestamp,
+            sample.cpu,
+            sample.rss,
+            sample.threads,
+            sample.read_bytes,
+            sample.written_bytes
+        ).execute(&mut *db).await?;
+    }
+    Ok(())
 }
 
-#[get("/terminate")]
-async fn terminate(game_runtime: &State<Arc<GameRuntime>>) -> String{
-    let game_runtime: Arc<GameRuntime> = game_runtime.inner().clone();
+pub fn terminate_game(game_runtime: &GameRuntime) {
     if game_runtime.game_running.load(Ordering::SeqCst) {
+       game_runtime.terminate_requested.store(true, Ordering::SeqCst);
        let pid = Pid::from_raw(game_runtime.pid.load(Ordering::Relaxed).try_into().unwrap());
-       signal::kill(pid, Signal::SIGTERM).expect("Process should exist!");
+       // The whole process group, under Proton, umu or bwrap the started process is only a wrapper of the game
+       if let Err(err) = signal::killpg(pid, Signal::SIGTERM) {
+           println!("Unable to terminate game: {}", err);
+       }
     }
-    game_runtime.game_running.store(false, Ordering::SeqCst);
-    "".to_string()
+    // `game_running` is cleared by the launcher / `watch_session` once the game actually exited,
+    // a launch in between would take over the runtime state of the still running game
+}
+
+pub fn emit(game_runtime: &GameRuntime, event: RuntimeEvent) {
+    // Fails only while nobody listens
+    let _ = game_runtime.events.send(event);
 }
 
-#[get("/history?<scope>&<date>")]
-async fn get_history( mut db: Connection<Db>, scope: &str, date: Option<String>) -> Option<json::Json<Vec<GameHistory>>> {
-    let mut param = "now".to_string();
-    if let Some(date) = date {
-        param = date;
+fn warn_limit(game_runtime: &GameRuntime, message: String) {
+    let mut limit_warning = game_runtime.limit_warning.lock().unwrap();
+    if limit_warning.as_ref() != Some(&message) {
+        *limit_warning = Some(message.clone());
+        emit(game_runtime, RuntimeEvent::LimitWarning { message });
     }
-    let intermediate_timestamps = match scope {
-        "week" => Some(sqlx::query_as!(IntermediateTimestamp,"SELECT 
-                date(timestamp_start, 'unixepoch') AS timestamp,
-                game,
-                SUM(timestamp_end - timestamp_start) AS total_playtime
-            FROM history
-            WHERE timestamp_start >= strftime('%s', ?1, '-7 days')
-            AND timestamp_start <= strftime('%s', ?1, '+1 days')
-            GROUP BY timestamp, game
-            ORDER BY timestamp DESC;", param).fetch_all(&mut **db).await.ok()?),
-        "month" => Some(sqlx::query_as!(IntermediateTimestamp, "SELECT strftime('%Y-%W', timestamp_start, 'unixepoch') AS timestamp, 
-                game, 
-                SUM(timestamp_end - timestamp_start) AS total_playtime 
-            FROM history 
-            WHERE strftime('%Y-%m', timestamp_start, 'unixepoch') = strftime('%Y-%m', ?1)
-            GROUP BY timestamp, game 
-            ORDER BY timestamp DESC;", param).fetch_all(&mut **db).await.ok()?),
+}
 
-        "year" => Some(sqlx::query_as!(IntermediateTimestamp, "SELECT 
-                strftime('%m', timestamp_start, 'unixepoch') AS timestamp,
-                game,
-                SUM(timestamp_end - timestamp_start) AS total_playtime
-            FROM history
-            WHERE strftime('%Y', timestamp_start, 'unixepoch') = strftime('%Y', ?1)
-            GROUP BY timestamp, game
-            ORDER BY timestamp DESC;", param).fetch_all(&mut **db).await.ok()?),
-        _ => None
-    };
-    
-    let r#type = match scope {
-        "day" => HistoryType::DAY,
-        "month" => HistoryType::WEEK,
-        "year" => HistoryType::MONTH,
-        _ => HistoryType::DAY
+// Warns once per threshold of `Settings::limit_warnings` and ends the game when a terminating limit is used up
+pub async fn enforce_limits(db: &mut SqliteConnection, settings: &Settings, game_runtime: &GameRuntime, subgame: i64, warned: &mut Vec<i64>) {
+    let running_since = game_runtime.running_since.load(Ordering::Relaxed) as i64;
+    let (limit, remaining) = match remaining_playtime(db, settings, subgame, Some(running_since)).await {
+        Ok(Some(tightest)) => tightest,
+        Ok(None) => return,
+        Err(err) => {
+            println!("Unable to check the playtime limits: {}", err);
+            warn_limit(game_runtime, "The playtime limits could not be checked!".to_string());
+            return;
+        },
     };
-     
-    let mut last_date = "".to_string();
-    let mut current_obj: GameHistory = GameHistory {
-        r#type: HistoryType::WEEK,
-        date: "".to_string(),
-        games: vec![],
-    };
-    let mut obj_list: Vec<GameHistory> = vec![];
+    if remaining <= 0 {
+        warn_limit(game_runtime, format!("The {} playtime limit is used up!", limit.period));
+        if limit.terminate {
+            println!("Playtime limit {} reached, terminating game!", limit.id);
+            terminate_game(game_runtime);
+        }
+        return;
+    }
+    for threshold in &settings.limit_warnings {
+        if remaining <= *threshold && !warned.contains(threshold) {
+            warned.push(*threshold);
+            let warning = format!("{} minutes left of the {} playtime limit", (remaining + 59) / 60, limit.period);
+            println!("{}", warning);
+            warn_limit(game_runtime, warning);
+        }
+    }
+}
 
+// Moves a session that ended into history, split into the segments the system was awake for.
+// Only the segment the game exited in gets the exit status and resource usage.
+pub async fn finish_session(db: &mut SqliteConnection, settings: &Settings, game_runtime: &GameRuntime, running: &RunningSession, segments: Vec<(i64, i64)>, exit_status: Option<ExitStatus>, resources: &ResourceSampler) {
+    let terminated = game_runtime.terminate_requested.load(Ordering::SeqCst);
+    // Whoever removes the running session records it, the launcher and a shutdown may both try
+    if running.id != 0 {
+        let removed = sqlx::query!("DELETE FROM running_sessions WHERE id = ?", running.id).execute(&mut *db).await;
+        if removed.is_ok_and(|result| result.rows_affected() == 0) {
+            return;
+        }
+    }
+    remove_cgroup(&cgroup_name(running.subgame, running.timestamp_start));
+    let row = sqlx::query!(
+        "UPDATE subgames SET last_launch = ? WHERE id = ?",
+        running.timestamp_start,
+        running.subgame
+    ).execute(&mut *db)
+    .await;
+    if let Ok(_row) = row {
+        println!("Updated game stats!");
+    } else {
+        println!("Failed to update game stats!");
+    }
 
-    for timestamp in intermediate_timestamps? {
-        let week = timestamp.timestamp?;
-        let game = timestamp.game;
-        let time = timestamp.total_playtime;
-        if last_date == week {
-            current_obj.games.push(HistoryGame{
-                id: game,
-                playtime: time
-            });
-        } else {
-            if current_obj.date != "".to_string() {
-                obj_list.push(current_obj);
-            }
-            current_obj = GameHistory{
-                r#type: r#type.clone(),
-                date: week.clone(),
-                games: vec![HistoryGame{
-                    id: game,
-                    playtime: time
-                }],
-            };
-            last_date = week;
+    println!("Adding to history...");
+    let played: i64 = segments.iter().map(|(start, end)| end - start).sum();
+    // A game that is gone within seconds most likely never started
+    let failed = !terminated && played < settings.failed_launch_threshold;
+    let signal = exit_status.and_then(|status| status.signal());
+    let exit_code = exit_status.and_then(|status| status.code());
+    if failed || (!terminated && (signal.is_some() || exit_code.is_some_and(|code| code != 0))) {
+        emit(game_runtime, RuntimeEvent::Crash {
+            session_id: running.id,
+            subgame: running.subgame,
+            seconds: played,
+            exit_code: exit_code.map(i64::from),
+            signal: signal.map(i64::from),
+            failed
+        });
+    } else {
+        emit(game_runtime, RuntimeEvent::Stop {
+            session_id: running.id,
+            subgame: running.subgame,
+            seconds: played,
+            exit_code: exit_code.map(i64::from),
+            terminated
+        });
+    }
+    if failed {
+        println!("Game exited after {}s, recording it as a failed launch", played);
+    } else if !terminated && let Some(signal) = signal {
+        println!("Game was killed by signal {}", signal);
+    }
+    let last = segments.len().saturating_sub(1);
+    let usage = resources.usage();
+    for (index, (start, end)) in segments.into_iter().enumerate() {
+        let usage = usage.as_ref().filter(|_| index == last);
+        let session = Session {
+            id: 0,
+            subgame: running.subgame,
+            timestamp_start: start,
+            timestamp_end: end,
+            exit_code: exit_code.filter(|_| index == last).map(i64::from),
+            launch_profile: Some(running.launch_profile.clone()),
+            manual: false,
+            signal: signal.filter(|_| index == last).map(i64::from),
+            terminated: terminated && index == last,
+            failed,
+            peak_cpu: usage.map(|usage| usage.peak_cpu),
+            average_cpu: usage.map(|usage| usage.average_cpu),
+            peak_rss: usage.map(|usage| usage.peak_rss),
+            average_rss: usage.map(|usage| usage.average_rss),
+            peak_threads: usage.map(|usage| usage.peak_threads),
+            read_bytes: usage.map(|usage| usage.read_bytes),
+            written_bytes: usage.map(|usage| usage.written_bytes),
+        };
+        match add_to_history(db, &session).await {
+            Ok(id) if usage.is_some() => if let Err(err) = add_resource_samples(db, id, &resources.samples()).await {
+                println!("Unable to save resource usage: {}", err);
+            },
+            Ok(_) => {},
+            Err(err) => println!("ERROR! {}", err),
         }
     }
-    if current_obj.date != "".to_string() {
-        obj_list.push(current_obj);
+    if update_playtime(db, settings, running.subgame).await.is_err() {
+        println!("Failed to update playtime!");
+    }
+}
+
+pub async fn runtime_status(db: &mut SqliteConnection, game_runtime: &GameRuntime) -> RuntimeStatus {
+    let game_running = game_runtime.game_running.load(Ordering::SeqCst);
+    let current_game = game_runtime.current_game.load(Ordering::Relaxed) as i64;
+    let running_since = game_runtime.running_since.load(Ordering::Relaxed) as i64;
+    let subgame_name = sqlx::query!("SELECT name FROM subgames WHERE id = ?", current_game)
+        .fetch_optional(&mut *db)
+        .await
+        .ok()
+        .flatten()
+        .map(|row| row.name);
+    let session_id = game_runtime.session_id.load(Ordering::Relaxed) as i64;
+    RuntimeStatus {
+        current_game,
+        game_running,
+        running_since,
+        limit_warning: game_runtime.limit_warning.lock().unwrap().clone(),
+        session_id: (game_running && session_id != 0).then_some(session_id),
+        subgame_name,
+        pid: game_running.then(|| game_runtime.pid.load(Ordering::Relaxed)),
+        elapsed: if game_running { Utc::now().timestamp() - running_since } else { 0 },
+        mounts: archive_mounts(),
+        resources: game_running.then(|| game_runtime.resources.lock().unwrap().clone()).flatten(),
     }
+}
 
+#[get("/status")]
+async fn get_status(game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> json::Json<RuntimeStatus> {
+    json::Json(runtime_status(&mut db, game_runtime).await)
+}
+
+// Server-sent events: the current status first, then a `RuntimeEvent` whenever something happens
+#[get("/events")]
+async fn get_events(game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>, mut shutdown: Shutdown) -> EventStream![] {
+    let status = runtime_status(&mut db, game_runtime).await;
+    let mut events = game_runtime.events.subscribe();
+    EventStream! {
+        yield Event::json(&status).event("status");
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            let name = match &event {
+                RuntimeEvent::Start { .. } => "start",
+                RuntimeEvent::Stop { .. } => "stop",
+                RuntimeEvent::Crash { .. } => "crash",
+                RuntimeEvent::LimitWarning { .. } => "limit_warning",
+            };
+            yield Event::json(&event).event(name);
+        }
+    }
+}
 
-    Some(json::Json(obj_list))
+#[get("/terminate")]
+async fn terminate(game_runtime: &State<Arc<GameRuntime>>) -> String{
+    terminate_game(game_runtime);
+    "".to_string()
 }
 
 #[get("/launch?<id>")]
-async fn launch_game(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> String {
+async fn launch_game(id: i64, game_runtime: &State<Arc<GameRuntime>>, settings: &State<Settings>, shutdown: Shutdown, mut db: Connection<Db>) -> String {
     println!("Starting Game!");
     let game_runtime: Arc<GameRuntime> = game_runtime.inner().clone();
+    match remaining_playtime(&mut db, settings, id, None).await {
+        Ok(Some((limit, remaining))) if remaining <= 0 => {
+            println!("Playtime limit {} is used up!", limit.id);
+            return format!("{{\"status\":\"FAILED: the {} playtime limit is used up!\"}}", limit.period);
+        },
+        Err(err) => {
+            println!("Unable to check the playtime limits: {}", err);
+            return "{\"status\":\"FAILED: the playtime limits could not be checked!\"}".to_string();
+        },
+        _ => {},
+    }
     if !game_runtime.game_running.swap(true, Ordering::SeqCst) {
         println!("Validated that no other game is running!");
+        *game_runtime.limit_warning.lock().unwrap() = None;
+        game_runtime.terminate_requested.store(false, Ordering::SeqCst);
+        *game_runtime.resources.lock().unwrap() = None;
             if let Some(compat_tool) = get_compat_tool(&mut db, id).await && let Some(game_config) = get_game_conf(&mut db, id).await {
-                let arguments: Vec<String> = vec![game_config.executable].iter().chain(game_config.arguments.iter()).cloned().collect();
-                let environment = compat_tool.environment.into_iter().chain(game_config.environment);
-                let game_start = Utc::now();
+                let command = game_command(&game_config, &compat_tool.executable);
+                let arguments = compat_arguments(&compat_tool, &game_config);
+                let environment: Vec<(String, String)> = compat_environment(&compat_tool, &game_config).into_iter()
+                    .chain(compat_tool.environment.clone())
+                    .chain(game_config.environment.clone())
+                    .collect();
                 let game_start_unix = Utc::now().timestamp();
                 game_runtime.running_since.store(game_start_unix as isize, Ordering::Relaxed);
                 game_runtime.current_game.store(id as isize, Ordering::Relaxed);
@@ -197,17 +352,28 @@ async fn launch_game(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Co
                 if game_config.archive_file != "".to_string() {
                     //TODO: [IMPL ARCHIVE SYSTEM] mount game's Squashfs
                 }
-                println!("Starting Process: {} run {:?}", compat_tool.executable, arguments);
+                println!("Starting Process: {} {:?}", compat_tool.executable, arguments);
                 println!("In working_directory: {}", game_config.working_directory);
                 println!("With Environment: {:?}", environment);
-                let child = Command::new(compat_tool.executable)
-                    .current_dir(game_config.working_directory)
-                    .arg("run")
+                let Some(mut command) = command else {
+                    println!("Sandbox is enabled but bwrap was not found!");
+                    game_runtime.game_running.store(false, Ordering::SeqCst);
+                    return "{\"status\":\"FAILED: bubblewrap (bwrap) is needed for the sandbox!\"}".to_string();
+                };
+                command.current_dir(&game_config.working_directory)
                     .args(arguments)
-                    .env("STEAM_COMPAT_DATA_PATH", game_config.game_prefix.clone())
-                    .env("STEAM_COMPAT_CLIENT_INSTALL_PATH", game_config.game_prefix)
                     .envs(environment)
-                    .spawn();
+                    // Own process group, so a Ctrl-C of the server doesn't reach a game that should keep running
+                    .process_group(0);
+                apply_limits(&mut command, &game_config.resource_limits, &cgroup_name(id, game_start_unix));
+                if game_config.block_network && !game_config.sandbox.enabled {
+                    println!("Starting game without network access");
+                    isolate_network(&mut command);
+                }
+                let child = command.spawn();
+                if let Err(err) = &child {
+                    println!("Unable to start game: {}", err);
+                }
                 if let Ok(mut child) = child {
                     if let Some(pid) = child.id() {
                         game_runtime.pid.store(pid, Ordering::Relaxed);
@@ -215,30 +381,53 @@ async fn launch_game(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Co
                         println!("Unable to get process ID!");
                     }
 
-                    child.wait().await.expect("Process should be running!"); // Wait for tha child
-                                                                             // to exit
+                    let running = persist_session(&mut db, id, game_runtime.pid.load(Ordering::Relaxed), game_start_unix, &compat_tool.name).await;
+                    game_runtime.session_id.store(running.id as isize, Ordering::Relaxed);
+                    emit(&game_runtime, RuntimeEvent::Start { session_id: running.id, subgame: id, pid: running.pid as u32 });
+
+                    // Wait for tha child to exit, checking the playtime limits and for suspends in between
+                    let mut warned = vec![];
+                    let mut segments = PlaySegments::resume(game_start_unix);
+                    let mut resources = ResourceSampler::default();
+                    let mut sample_check = tokio::time::interval(Duration::from_secs(SAMPLE_INTERVAL));
+                    let mut limit_check = tokio::time::interval(Duration::from_secs(30));
+                    let mut suspend_check = tokio::time::interval(Duration::from_secs(5));
+                    let mut heartbeat_check = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+                    let mut shutting_down = false;
+                    let exit_status = loop {
+                        tokio::select! {
+                            status = child.wait() => break Some(status.expect("Process should be running!")),
+                            _ = shutdown.clone(), if !shutting_down => {
+                                shutting_down = true;
+                                if settings.shutdown_action == "terminate" {
+                                    println!("Server is shutting down, terminating game!");
+                                    terminate_game(&game_runtime);
+                                } else {
+                                    println!("Server is shutting down, leaving the game running");
+                                    break None;
+                                }
+                            },
+                            _ = limit_check.tick() => enforce_limits(&mut db, settings, &game_runtime, id, &mut warned).await,
+                            _ = heartbeat_check.tick() => heartbeat(&mut db, &running, &resources).await,
+                            _ = sample_check.tick() => {
+                                resources.sample(running.pid as u32);
+                                *game_runtime.resources.lock().unwrap() = resources.usage();
+                            },
+                            _ = suspend_check.tick() => if let Some(suspended) = segments.check() {
+                                println!("System was suspended for {}s, not counting it as playtime", suspended / 1000);
+                                // `running_since` is only used to show / limit the time played so far
+                                game_runtime.running_since.fetch_add((suspended / 1000) as isize, Ordering::Relaxed);
+                            },
+                        }
+                    };
                                                                             
                     game_runtime.game_running.store(false, Ordering::SeqCst);
-                    let playtime = ((Utc::now() - game_start).num_minutes() as f32) / 60.0;
-                    let row = sqlx::query!(
-                        "UPDATE subgames SET playtime = COALESCE(playtime, 0) + ?, last_launch = ? WHERE id = ?",
-                        playtime, 
-                        game_start_unix, 
-                        id
-                    ).execute(&mut **db)
-                    .await;
-                    if let Ok(_row) = row {
-                        println!("Updated game stats!");
-
+                    if exit_status.is_some() || running.id == 0 {
+                        finish_session(&mut db, settings, &game_runtime, &running, segments.finish(), exit_status, &resources).await;
                     } else {
-                        println!("Failed to update game stats!");
+                        // Left running: the session stays with a last heartbeat and the next start follows the game again
+                        heartbeat(&mut db, &running, &resources).await;
                     }
-
-                    println!("Adding to history...");
-                    add_to_history(&mut db, game_start.timestamp(), Utc::now().timestamp(), id).await.unwrap_or_else(|err|{
-                        println!("ERROR! {}", err);
-                        ()
-                    });
                 } else {
                     game_runtime.game_running.store(false, Ordering::SeqCst);
                 }        
@@ -253,5 +442,5 @@ async fn launch_game(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Co
 }
 
 pub fn routes() -> Vec<rocket::Route> {
-    routes![get_status, terminate, launch_game, get_history]
+    routes![get_status, get_events, terminate, launch_game]
 }
@@ -0,0 +1,233 @@
+use rocket::{get, routes, State};
+use rocket::http::ContentType;
+use rocket::response::stream::ByteStream;
+use rocket::serde::json;
+use rocket::futures::StreamExt;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket_db_pools::{Connection, sqlx};
+
+use crate::structures::{Db, ExportSession, Settings};
+use crate::time_helper::time_zone;
+
+fn format_time(timestamp: i64, tz: &Tz) -> String {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default().with_timezone(tz).to_rfc3339()
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    text.to_string()
+}
+
+fn csv_line(session: &ExportSession) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{}\n",
+        session.id,
+        csv_field(&session.game),
+        csv_field(&session.subgame),
+        session.start,
+        session.end,
+        session.duration,
+        session.exit_code.map(|code| code.to_string()).unwrap_or_default(),
+        session.manual
+    )
+}
+
+// Every session between `from` and `to` (unix timestamps), times are given in the `tz` parameter or configured zone.
+// Rows are streamed straight from the database, so exporting the whole history doesn't buffer it in memory.
+#[get("/export/history?<format>&<from>&<to>&<tz>")]
+async fn export_history(mut db: Connection<Db>, settings: &State<Settings>, format: Option<&str>, from: Option<i64>, to: Option<i64>, tz: Option<&str>) -> Option<(ContentType, ByteStream![Vec<u8>])> {
+    let tz = time_zone(settings, tz)?;
+    let csv = match format.unwrap_or("csv") {
+        "csv" => true,
+        "json" => false,
+        _ => return None
+    };
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(i64::MAX);
+    let content_type = if csv { ContentType::CSV } else { ContentType::JSON };
+
+    Some((content_type, ByteStream! {
+        let mut rows = sqlx::query!(
+            "SELECT h.id, g.name AS game, s.name AS subgame, h.timestamp_start, h.timestamp_end, h.exit_code, h.manual
+            FROM history h
+            JOIN subgames s ON s.id = h.subgame
+            JOIN games g ON g.id = s.parent
+            WHERE h.timestamp_end > ? AND h.timestamp_start < ?
+            ORDER BY h.timestamp_start ASC",
+            from,
+            to
+        ).fetch(&mut **db);
+
+        yield if csv {
+            b"id,game,subgame,start,end,duration,exit_code,manual\n".to_vec()
+        } else {
+            b"[".to_vec()
+        };
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            // A broken off export must not look complete, so neither the closing bracket nor anything valid follows
+            let row = match row {
+                Ok(row) => row,
+                Err(err) => {
+                    println!("History export failed: {}", err);
+                    yield format!("\nERROR: the export is incomplete ({})\n", err).into_bytes();
+                    return;
+                },
+            };
+            let session = ExportSession {
+                id: row.id,
+                game: row.game,
+                subgame: row.subgame,
+                start: format_time(row.timestamp_start, &tz),
+                end: format_time(row.timestamp_end, &tz),
+                duration: row.timestamp_end - row.timestamp_start,
+                exit_code: row.exit_code,
+                manual: row.manual
+            };
+            if csv {
+                yield csv_line(&session).into_bytes();
+            } else {
+                let separator = if first { "" } else { "," };
+                yield format!("{}{}", separator, json::serde_json::to_string(&session).unwrap_or_default()).into_bytes();
+            }
+            first = false;
+        }
+        if !csv {
+            yield b"]".to_vec();
+        }
+    }))
+}
+
+fn ics_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default().format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// Content lines longer than 75 octets have to be folded (RFC 5545, 3.1)
+fn ics_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+// iCalendar feed with one event per session, can be imported or subscribed to by calendar apps.
+// Manual sessions are left out, as they don't say when someone actually played.
+#[get("/export/history.ics?<game>&<subgame>")]
+async fn export_calendar(mut db: Connection<Db>, game: Option<i64>, subgame: Option<i64>) -> (ContentType, ByteStream![Vec<u8>]) {
+    (ContentType::Calendar, ByteStream! {
+        let mut rows = sqlx::query!(
+            "SELECT h.id, g.name AS game, s.name AS subgame, h.timestamp_start, h.timestamp_end
+            FROM history h
+            JOIN subgames s ON s.id = h.subgame
+            JOIN games g ON g.id = s.parent
+            WHERE h.manual = false
+              AND (?1 IS NULL OR s.parent = ?1)
+              AND (?2 IS NULL OR h.subgame = ?2)
+            ORDER BY h.timestamp_start ASC",
+            game,
+            subgame
+        ).fetch(&mut **db);
+
+        yield "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//game_archive//play history//EN\r\nX-WR-CALNAME:Play history\r\n".as_bytes().to_vec();
+        while let Some(row) = rows.next().await {
+            // Without END:VCALENDAR calendar apps reject the feed instead of dropping the missing sessions
+            let row = match row {
+                Ok(row) => row,
+                Err(err) => {
+                    println!("Calendar export failed: {}", err);
+                    return;
+                },
+            };
+            let minutes = (row.timestamp_end - row.timestamp_start) / 60;
+            let event = [
+                "BEGIN:VEVENT".to_string(),
+                format!("UID:session-{}@game_archive", row.id),
+                format!("DTSTAMP:{}", ics_time(row.timestamp_end)),
+                format!("DTSTART:{}", ics_time(row.timestamp_start)),
+                format!("DTEND:{}", ics_time(row.timestamp_end)),
+                format!("SUMMARY:{}", ics_text(&row.subgame)),
+                format!("DESCRIPTION:{}", ics_text(&format!("{} - {}h {}min", row.game, minutes / 60, minutes % 60))),
+                "END:VEVENT".to_string(),
+            ];
+            yield event.iter().map(|line| ics_line(line)).collect::<String>().into_bytes();
+        }
+        yield "END:VCALENDAR\r\n".as_bytes().to_vec();
+    })
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![export_history, export_calendar]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("Portal 2"), "Portal 2");
+        assert_eq!(csv_field("Hello, World"), "\"Hello, World\"");
+        assert_eq!(csv_field("The \"Game\""), "\"The \"\"Game\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_line_has_every_column() {
+        let session = ExportSession {
+            id: 7,
+            game: "a, b".to_string(),
+            subgame: "c".to_string(),
+            start: "2024-05-10T23:30:00+02:00".to_string(),
+            end: "2024-05-11T00:45:00+02:00".to_string(),
+            duration: 4500,
+            exit_code: None,
+            manual: false,
+        };
+        assert_eq!(csv_line(&session), "7,\"a, b\",c,2024-05-10T23:30:00+02:00,2024-05-11T00:45:00+02:00,4500,,false\n");
+    }
+
+    #[test]
+    fn ics_text_is_escaped() {
+        assert_eq!(ics_text("a, b; c\\d\ne"), "a\\, b\\; c\\\\d\\ne");
+        assert_eq!(ics_time(0), "19700101T000000Z");
+    }
+
+    #[test]
+    fn long_ics_lines_are_folded_at_75_octets() {
+        let line = format!("SUMMARY:{}", "x".repeat(100));
+        let folded = ics_line(&line);
+        let parts: Vec<&str> = folded.trim_end_matches("\r\n").split("\r\n").collect();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].len(), 75);
+        assert!(parts[1].starts_with(' '));
+        assert_eq!(format!("{}{}", parts[0], &parts[1][1..]), line);
+        assert_eq!(ics_line("SHORT"), "SHORT\r\n");
+    }
+
+    #[test]
+    fn folding_doesnt_split_characters() {
+        // 73 octets, then a 3 octet character that no longer fits
+        let line = format!("{}€", "x".repeat(73));
+        let folded = ics_line(&line);
+        assert!(folded.starts_with(&format!("{}\r\n €", "x".repeat(73))));
+        assert!(folded.split("\r\n").all(|part| part.len() <= 75));
+    }
+}
@@ -1,53 +1,90 @@
-use rocket::{get, post, routes, delete};
+use rocket::{get, post, routes, delete, State};
 use rocket::http::Status;
 use rocket::serde::json;
 use rocket_db_pools::sqlx;
 use rocket_db_pools::Connection;
-use crate::structures::{Db, MetaGame, SubGame, Game};
+use chrono::Utc;
+use crate::routes::history::{add_manual_playtime, update_playtime};
+use crate::structures::{Db, MetaGame, Settings, SubGame, Game};
 
+// Playtime is derived from history, a higher playtime than recorded is added as a manual session.
+// A lower one is rejected with 400, recorded playtime can only be taken back by editing or deleting sessions.
 #[post("/subgame", format = "json", data = "<data>")]
-async fn post_subgame(mut db: Connection<Db>, data: json::Json<SubGame>) -> Option<json::Json<SubGame>> {
-    if data.id == 0 {
+async fn post_subgame(mut db: Connection<Db>, settings: &State<Settings>, data: json::Json<SubGame>) -> Result<json::Json<SubGame>, Status> {
+    let mut id = data.id;
+    let recorded = if id == 0 {
+        0
+    } else {
+        sqlx::query!(
+            "SELECT playtime FROM subgames WHERE id = ?",
+            data.id
+        ).fetch_optional(&mut **db)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::NotFound)?
+        .playtime
+        .unwrap_or(0)
+    };
+    let missing = data.playtime.map_or(0, |playtime| playtime - recorded);
+    if missing < 0 {
+        return Err(Status::BadRequest);
+    }
+
+    if id == 0 {
         let row = sqlx::query!(
-            "INSERT INTO subgames (name,playtime,last_launch,is_archived,parent) VALUES (?,?,?,?,?); SELECT last_insert_rowid() AS id;",
+            "INSERT INTO subgames (name,last_launch,is_archived,parent) VALUES (?,?,?,?); SELECT last_insert_rowid() AS id;",
             data.name,
-            data.playtime,
             data.last_launch,
             data.is_archived,
             data.parent
         ).fetch_optional(&mut **db)
         .await
-        .ok()??;
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::InternalServerError)?;
+        id = row.id as i64;
+    } else {
+        sqlx::query!(
+            "UPDATE subgames SET name = ?, last_launch = ?, is_archived = ?, parent = ? WHERE id = ?",
+            data.name,
+            data.last_launch,
+            data.is_archived,
+            data.parent,
+            data.id
+        ).execute(&mut **db)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    }
 
-        return Some(json::Json(SubGame{
-            id: row.id as i64,
-            name: data.name.clone(),
-            playtime: data.playtime,
-            last_launch: data.last_launch,
-            is_archived: data.is_archived,
-            parent: data.parent
-        }));
+    if missing > 0 {
+        add_manual_playtime(&mut db, id, missing).await.map_err(|_| Status::InternalServerError)?;
+        update_playtime(&mut db, settings, id).await.map_err(|_| Status::InternalServerError)?;
     }
-    sqlx::query!(
-        "UPDATE subgames SET name = ?, playtime = ?, last_launch = ?, is_archived = ?, parent = ? WHERE id = ?",
-        data.name,
-        data.playtime,
-        data.last_launch,
-        data.is_archived,
-        data.parent,
-        data.id
-    ).execute(&mut **db)
+
+    let subgame = sqlx::query_as!(
+        SubGame,
+        r#"SELECT s.id AS "id!", s.name, s.playtime, s.last_launch, s.is_archived, s.parent AS "parent!", s.finished,
+                  st.crash_count AS "crash_count!: i64", st.last_launch_failed AS "last_launch_failed!: bool"
+        FROM subgames s
+        JOIN subgame_status st ON st.subgame = s.id
+        WHERE s.id = ?"#,
+        id
+    ).fetch_optional(&mut **db)
     .await
-    .ok()?;
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)?;
 
-    Some(data)
+    Ok(json::Json(subgame))
 }
 
 #[get("/subgame?<id>")]
 async fn get_subgame(id: i64, mut db: Connection<Db>) -> Option<json::Json<SubGame>> {
     let subgame = sqlx::query_as!(
         SubGame,
-        "SELECT id, name, playtime, last_launch, is_archived, parent FROM subgames WHERE id = ?",
+        r#"SELECT s.id AS "id!", s.name, s.playtime, s.last_launch, s.is_archived, s.parent AS "parent!", s.finished,
+                  st.crash_count AS "crash_count!: i64", st.last_launch_failed AS "last_launch_failed!: bool"
+        FROM subgames s
+        JOIN subgame_status st ON st.subgame = s.id
+        WHERE s.id = ?"#,
         id
     ).fetch_optional(&mut **db)
     .await
@@ -56,13 +93,40 @@ async fn get_subgame(id: i64, mut db: Connection<Db>) -> Option<json::Json<SubGa
     Some(json::Json(subgame))
 }
 
+// Marks a subgame as played through, at `timestamp` or now
+#[post("/subgame_finished?<id>&<timestamp>")]
+async fn post_subgame_finished(id: i64, timestamp: Option<i64>, mut db: Connection<Db>) -> Option<Status> {
+    let timestamp = timestamp.unwrap_or(Utc::now().timestamp());
+    sqlx::query!(
+        "UPDATE subgames SET finished = ? WHERE id = ?",
+        timestamp,
+        id
+    ).execute(&mut **db)
+    .await
+    .ok()?;
+
+    Some(Status::Ok)
+}
+
+#[delete("/subgame_finished?<id>")]
+async fn delete_subgame_finished(id: i64, mut db: Connection<Db>) -> Option<Status> {
+    sqlx::query!(
+        "UPDATE subgames SET finished = NULL WHERE id = ?",
+        id
+    ).execute(&mut **db)
+    .await
+    .ok()?;
+
+    Some(Status::Gone)
+}
+
 #[get("/gamemeta?<id>")]
 async fn get_gamemeta(id: i64, mut db: Connection<Db>) -> Option<json::Json<MetaGame>> {
     let game_meta = sqlx::query_as!(
         MetaGame,
         r#"SELECT g.id AS id,
                   g.name AS name, 
-                  SUM(s.playtime) AS playtime, 
+                  SUM(s.playtime) AS "playtime: i64", 
                   MAX(s.last_launch) AS last_launch, 
                   MIN(s.is_archived) AS is_archived 
             FROM games g 
@@ -81,7 +145,7 @@ async fn get_games(mut db: Connection<Db>) -> Option<json::Json<Vec<MetaGame>>>
     let rows = sqlx::query!(
         r#"SELECT g.id AS id,
                   g.name AS name, 
-                  SUM(s.playtime) AS playtime, 
+                  SUM(s.playtime) AS "playtime: i64", 
                   MAX(s.last_launch) AS last_launch,
                   COALESCE(MIN(s.is_archived), 0) AS is_archived
             FROM games g 
@@ -140,7 +204,11 @@ async fn post_games(mut db: Connection<Db>, data: json::Json<Game>) -> Option<js
 async fn get_game(id: i64, mut db: Connection<Db>) -> Option<json::Json<Game>> {
     let subgames = sqlx::query_as!(
         SubGame,
-        "SELECT id, name, playtime, last_launch, is_archived, parent FROM subgames WHERE parent = ?",
+        r#"SELECT s.id AS "id!", s.name, s.playtime, s.last_launch, s.is_archived, s.parent AS "parent!", s.finished,
+                  st.crash_count AS "crash_count!: i64", st.last_launch_failed AS "last_launch_failed!: bool"
+        FROM subgames s
+        JOIN subgame_status st ON st.subgame = s.id
+        WHERE s.parent = ?"#,
         id
     ).fetch_all(&mut **db)
     .await
@@ -184,5 +252,5 @@ async fn delete_game(id: i64, mut db: Connection<Db>) -> Option<Status> {
 }
 
 pub fn routes() -> Vec<rocket::Route> {
-    routes![post_subgame, post_games, get_gamemeta, get_game, get_games, get_subgame, delete_subgame, delete_game]
+    routes![post_subgame, post_games, get_gamemeta, get_game, get_games, get_subgame, delete_subgame, delete_game, post_subgame_finished, delete_subgame_finished]
 }
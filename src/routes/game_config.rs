@@ -3,7 +3,8 @@ use rocket::http::Status;
 use std::collections::HashMap;
 use rocket_db_pools::Connection;
 use rocket::serde::json;
-use crate::structures::{Db, GameConfig, CompatTool};
+use crate::structures::{Db, GameConfig, CompatTool, CompatToolCandidate};
+use crate::compat_helper::discover_compat_tools;
 use rocket_db_pools::sqlx;
 use serde::{Serialize, Deserialize};
 
@@ -11,7 +12,8 @@ use serde::{Serialize, Deserialize};
 #[derive(Deserialize, Serialize)]
 struct MetaCompatTool {
     pub id: i64,
-    pub name: String
+    pub name: String,
+    pub kind: String,
 }
 
 #[get("/launch_config?<id>")]
@@ -27,23 +29,47 @@ async fn get_game_config(id: i64, mut db:  Connection<Db>) -> Option<String>{
     )
 }
 
+// Fields missing in `update` keep their stored value, the edit page doesn't know all of them.
+// Fields that are sent replace the stored ones as a whole, so variables removed from `environment` stay removed.
+fn merge_config(stored: &mut json::Value, update: json::Value) {
+    match (stored, update) {
+        (json::Value::Object(stored), json::Value::Object(update)) => stored.extend(update),
+        (stored, update) => *stored = update,
+    }
+}
+
 #[post("/launch_config?<id>", format="json", data="<data>")]
-async fn post_game_config(mut db: Connection<Db>, data: json::Json<GameConfig>, id: i64) -> Option<json::Json<GameConfig>>{
-    let stringified_json = json::serde_json::to_string_pretty(&data.clone().into_inner()).ok()?;
+async fn post_game_config(mut db: Connection<Db>, data: json::Json<json::Value>, id: i64) -> Result<json::Json<GameConfig>, Status> {
+    let stored = sqlx::query!(
+        "SELECT launch_config FROM subgames WHERE id = ?",
+        id
+    ).fetch_optional(&mut **db)
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .ok_or(Status::NotFound)?
+    .launch_config;
+    let mut config = json::serde_json::from_str::<json::Value>(&stored).unwrap_or(json::Value::Null);
+    merge_config(&mut config, data.into_inner());
+    let config = json::serde_json::from_value::<GameConfig>(config).map_err(|_| Status::BadRequest)?;
+    // cpu.max doesn't take a quota of 0
+    if config.resource_limits.cpu_quota == Some(0) {
+        return Err(Status::BadRequest);
+    }
+    let stringified_json = json::serde_json::to_string_pretty(&config).map_err(|_| Status::InternalServerError)?;
     sqlx::query!(
         "UPDATE subgames SET launch_config = ? WHERE id = ?",
         stringified_json,
         id
     ).execute(&mut **db)
     .await
-    .ok()?;
-    Some(data)
+    .map_err(|_| Status::InternalServerError)?;
+    Ok(json::Json(config))
 }
 
 #[get("/compat_tools?<id>")]
 async fn get_compat_tool(id: i64, mut db: Connection<Db>) -> Option<json::Json<CompatTool>> {
     let rows = sqlx::query!(
-        "SELECT id, name, executable, environment FROM compat_tools WHERE id = ?",
+        "SELECT id, name, executable, environment, kind, proton_tool FROM compat_tools WHERE id = ?",
         id
     ).fetch_optional(&mut **db)
     .await
@@ -54,7 +80,9 @@ async fn get_compat_tool(id: i64, mut db: Connection<Db>) -> Option<json::Json<C
             id: rows.id,
             name: rows.name,
             executable: rows.executable,
-            environment: json::serde_json::from_str::<HashMap<String, String>>(&rows.environment?).ok()? 
+            environment: json::serde_json::from_str::<HashMap<String, String>>(&rows.environment?).ok()?,
+            kind: rows.kind,
+            proton_tool: rows.proton_tool,
         })
     )
 }
@@ -77,7 +105,7 @@ async fn get_compat_tools(mut db: Connection<Db>) -> Option<json::Json<Vec<MetaC
     Some(json::Json(
         sqlx::query_as!(
              MetaCompatTool,
-             "SELECT id, name FROM compat_tools"
+             "SELECT id, name, kind FROM compat_tools"
          ).fetch_all(&mut **db)
          .await
          .ok()?
@@ -89,10 +117,12 @@ async fn post_compat_tools(mut db: Connection<Db>, data: json::Json<CompatTool>)
     let env_data = json::serde_json::to_string_pretty(&data.environment).ok()?;
     if data.id != 0 {
         sqlx::query!(
-            "UPDATE compat_tools SET name = ?, executable = ?, environment = ? WHERE id = ?",
+            "UPDATE compat_tools SET name = ?, executable = ?, environment = ?, kind = ?, proton_tool = ? WHERE id = ?",
             data.name,
             data.executable,
             env_data,
+            data.kind,
+            data.proton_tool,
             data.id
         ).execute(&mut **db)
         .await
@@ -101,10 +131,12 @@ async fn post_compat_tools(mut db: Connection<Db>, data: json::Json<CompatTool>)
         return Some(data)
     }
     let row = sqlx::query!(
-        "INSERT INTO compat_tools (name,executable,environment) VALUES (?,?,?); SELECT last_insert_rowid() AS id;",
+        "INSERT INTO compat_tools (name,executable,environment,kind,proton_tool) VALUES (?,?,?,?,?); SELECT last_insert_rowid() AS id;",
         data.name,
         data.executable,
         env_data,
+        data.kind,
+        data.proton_tool,
     ).fetch_optional(&mut **db)
     .await
     .ok()??;
@@ -113,11 +145,52 @@ async fn post_compat_tools(mut db: Connection<Db>, data: json::Json<CompatTool>)
             id: row.id as i64,
             name: data.name.clone(),
             executable: data.executable.clone(),
-            environment: data.environment.clone() 
+            environment: data.environment.clone(),
+            kind: data.kind.clone(),
+            proton_tool: data.proton_tool,
     }));
 
 }
 
+// Compat tools installed on this system, `id` is set for the ones already in `compat_tools`
+async fn discovered_tools(db: &mut Connection<Db>) -> Option<Vec<CompatToolCandidate>> {
+    // Scanning the directories and asking Wine for its version blocks
+    let mut candidates = tokio::task::spawn_blocking(discover_compat_tools).await.ok()?;
+    for candidate in &mut candidates {
+        candidate.id = sqlx::query_scalar!(
+            "SELECT id FROM compat_tools WHERE executable = ?",
+            candidate.executable
+        ).fetch_optional(&mut ***db)
+        .await
+        .ok()?;
+    }
+    Some(candidates)
+}
+
+#[get("/compat_tools/discover")]
+async fn get_compat_discover(mut db: Connection<Db>) -> Option<json::Json<Vec<CompatToolCandidate>>> {
+    Some(json::Json(discovered_tools(&mut db).await?))
+}
+
+// Adds the installed compat tools that are not in `compat_tools` yet
+#[post("/compat_tools/discover")]
+async fn post_compat_discover(mut db: Connection<Db>) -> Option<json::Json<Vec<CompatToolCandidate>>> {
+    let mut candidates = discovered_tools(&mut db).await?;
+    for candidate in candidates.iter_mut().filter(|candidate| candidate.id.is_none()) {
+        let row = sqlx::query!(
+            "INSERT INTO compat_tools (name, executable, environment, kind) VALUES (?, ?, '{}', ?); SELECT last_insert_rowid() AS id;",
+            candidate.name,
+            candidate.executable,
+            candidate.kind
+        ).fetch_one(&mut **db)
+        .await
+        .ok()?;
+        println!("Registered compat tool {}", candidate.name);
+        candidate.id = Some(row.id as i64);
+    }
+    Some(json::Json(candidates))
+}
+
 #[delete("/compat_tools?<id>")]
 async fn delete_compat_tools(id: i64, mut db: Connection<Db>) -> Option<Status> {
     sqlx::query!(
@@ -131,5 +204,5 @@ async fn delete_compat_tools(id: i64, mut db: Connection<Db>) -> Option<Status>
 }
 
 pub fn routes() -> Vec<rocket::Route> {
-    routes![get_game_config, post_game_config, get_compat_tool, post_compat_tools, get_compat_tools, get_compat_assign, delete_compat_tools]
+    routes![get_game_config, post_game_config, get_compat_tool, post_compat_tools, get_compat_tools, get_compat_assign, get_compat_discover, post_compat_discover, delete_compat_tools]
 }
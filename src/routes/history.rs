@@ -0,0 +1,401 @@
+use rocket::{get, post, delete, routes, State};
+use rocket::http::Status;
+use rocket::serde::json;
+use chrono::{Datelike, Days, Months, NaiveDate, Utc};
+use std::collections::BTreeMap;
+use rocket_db_pools::{Connection, sqlx};
+use rocket_db_pools::sqlx::{SqliteConnection, SqlitePool};
+
+use crate::structures::{Db, ResourceSample, Session, Settings, GameHistory, HistoryGame, HistoryType};
+use crate::time_helper::{split_into_buckets, start_of_day, time_zone, today};
+
+// Most buckets a history request may be split into
+const MAX_HISTORY_BUCKETS: i64 = 1000;
+
+// `subgames.playtime` is only ever derived from history, call this after every change to a subgame's sessions
+pub async fn update_playtime(db: &mut SqliteConnection, settings: &Settings, subgame: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE subgames SET playtime = (
+            SELECT SUM(timestamp_end - timestamp_start) FROM history WHERE subgame = ?1 AND (failed = false OR ?2)
+        ) WHERE id = ?1",
+        subgame,
+        settings.count_failed_launches
+    ).execute(&mut *db)
+    .await?;
+    Ok(())
+}
+
+// Sessions only store whether they were a failed launch, so a changed `failed_launch_threshold` or
+// `count_failed_launches` is applied to the whole history and all playtimes when the server starts
+pub async fn apply_failed_launch_threshold(db: &SqlitePool, settings: &Settings) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE history SET failed = (manual = false AND terminated = false AND timestamp_end - timestamp_start < ?)",
+        settings.failed_launch_threshold
+    ).execute(db)
+    .await?;
+    sqlx::query!(
+        "UPDATE subgames SET playtime = (
+            SELECT SUM(timestamp_end - timestamp_start) FROM history WHERE subgame = subgames.id AND (failed = false OR ?)
+        )",
+        settings.count_failed_launches
+    ).execute(db)
+    .await?;
+    Ok(())
+}
+
+// Playtime that was entered by hand without a date (e.g. through the subgame form) ends now
+pub async fn add_manual_playtime(db: &mut Connection<Db>, subgame: i64, seconds: i64) -> Result<(), sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let start = now - seconds;
+    sqlx::query!(
+        "INSERT INTO history (subgame, timestamp_start, timestamp_end, manual) VALUES (?, ?, ?, true)",
+        subgame,
+        start,
+        now
+    ).execute(&mut ***db)
+    .await?;
+    Ok(())
+}
+
+fn highest<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b > a { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+fn sum(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    if a.is_none() && b.is_none() {
+        return None;
+    }
+    Some(a.unwrap_or(0) + b.unwrap_or(0))
+}
+
+// Combine sessions of the same subgame that are at most `window` seconds apart.
+// The merged session keeps the id of its first and the exit code / profile of its last part,
+// peaks are the highest and I/O the sum of all parts.
+fn merge_sessions(sessions: Vec<Session>, window: i64) -> Vec<Session> {
+    let mut merged: Vec<Session> = vec![];
+    for session in sessions {
+        if let Some(last) = merged.iter_mut().rev().find(|s| s.subgame == session.subgame)
+            && session.timestamp_start - last.timestamp_end <= window {
+            last.timestamp_end = last.timestamp_end.max(session.timestamp_end);
+            last.exit_code = session.exit_code;
+            last.launch_profile = session.launch_profile;
+            last.manual &= session.manual;
+            last.signal = session.signal;
+            last.terminated = session.terminated;
+            last.failed &= session.failed;
+            last.peak_cpu = highest(last.peak_cpu, session.peak_cpu);
+            last.peak_rss = highest(last.peak_rss, session.peak_rss);
+            last.peak_threads = highest(last.peak_threads, session.peak_threads);
+            last.average_cpu = last.average_cpu.or(session.average_cpu);
+            last.average_rss = last.average_rss.or(session.average_rss);
+            last.read_bytes = sum(last.read_bytes, session.read_bytes);
+            last.written_bytes = sum(last.written_bytes, session.written_bytes);
+            continue;
+        }
+        merged.push(session);
+    }
+    merged
+}
+
+// `from` and `to` are unix timestamps, `merged` groups sessions using the configured merge window
+#[get("/sessions?<subgame>&<from>&<to>&<merged>")]
+async fn get_sessions(mut db: Connection<Db>, settings: &State<Settings>, subgame: Option<i64>, from: Option<i64>, to: Option<i64>, merged: Option<bool>) -> Option<json::Json<Vec<Session>>> {
+    let sessions = sqlx::query_as!(
+        Session,
+        "SELECT id, subgame, timestamp_start, timestamp_end, exit_code, launch_profile, manual, signal, terminated, failed,
+                peak_cpu, average_cpu, peak_rss, average_rss, peak_threads, read_bytes, written_bytes
+        FROM history
+        WHERE (?1 IS NULL OR subgame = ?1)
+          AND (?2 IS NULL OR timestamp_end >= ?2)
+          AND (?3 IS NULL OR timestamp_start <= ?3)
+        ORDER BY timestamp_start ASC",
+        subgame,
+        from,
+        to
+    ).fetch_all(&mut **db)
+    .await
+    .ok()?;
+
+    if merged.unwrap_or(false) {
+        return Some(json::Json(merge_sessions(sessions, settings.merge_window)));
+    }
+    Some(json::Json(sessions))
+}
+
+// `scope` picks the window around `date` (YYYY-MM-DD, defaults to today) and the bucket size:
+// day -> hours, week -> days, month -> weeks, year -> months. `from` and `to` (unix timestamps) override the window.
+// `group` selects whether playtime is summed per subgame (default) or per parent game.
+// Sessions that span several buckets are split between them, buckets follow the `tz` parameter or configured zone.
+// Ranges that would need more than `MAX_HISTORY_BUCKETS` buckets are rejected with 400.
+#[get("/history?<scope>&<date>&<from>&<to>&<group>&<tz>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_history(mut db: Connection<Db>, settings: &State<Settings>, scope: &str, date: Option<String>, from: Option<i64>, to: Option<i64>, group: Option<&str>, tz: Option<&str>) -> Result<json::Json<Vec<GameHistory>>, Status> {
+    let tz = time_zone(settings, tz).ok_or(Status::BadRequest)?;
+    let by_game = match group.unwrap_or("subgame") {
+        "game" => true,
+        "subgame" => false,
+        _ => return Err(Status::BadRequest)
+    };
+    let date = match date {
+        Some(date) => NaiveDate::parse_from_str(&date, "%Y-%m-%d").map_err(|_| Status::BadRequest)?,
+        None => today(&tz)
+    };
+    let (r#type, window_start, window_end) = match scope {
+        "day" => (HistoryType::HOUR, date, date + Days::new(1)),
+        "week" => (HistoryType::DAY, date - Days::new(7), date + Days::new(1)),
+        "month" => {
+            let first = date.with_day(1).ok_or(Status::BadRequest)?;
+            (HistoryType::WEEK, first, first + Months::new(1))
+        },
+        "year" => {
+            let first = date.with_ordinal(1).ok_or(Status::BadRequest)?;
+            (HistoryType::MONTH, first, first + Months::new(12))
+        },
+        _ => return Err(Status::BadRequest)
+    };
+    let from = from.unwrap_or(start_of_day(window_start, &tz));
+    let to = to.unwrap_or(start_of_day(window_end, &tz));
+    // A custom range could ask for any number of buckets, e.g. years split into hours
+    let bucket_length = match r#type {
+        HistoryType::HOUR => 3600,
+        HistoryType::DAY => 24 * 3600,
+        HistoryType::WEEK => 7 * 24 * 3600,
+        HistoryType::MONTH => 28 * 24 * 3600
+    };
+    if to.saturating_sub(from) / bucket_length > MAX_HISTORY_BUCKETS {
+        return Err(Status::BadRequest);
+    }
+
+    let sessions = sqlx::query!(
+        "SELECT h.timestamp_start, h.timestamp_end, h.subgame, s.parent
+        FROM history h
+        JOIN subgames s ON s.id = h.subgame
+        WHERE h.timestamp_end > ? AND h.timestamp_start < ? AND (h.failed = false OR ?)",
+        from,
+        to,
+        settings.count_failed_launches
+    ).fetch_all(&mut **db)
+    .await
+    .map_err(|_| Status::InternalServerError)?;
+
+    let mut buckets: BTreeMap<String, BTreeMap<i64, i64>> = BTreeMap::new();
+    for session in sessions {
+        let id = if by_game { session.parent } else { session.subgame };
+        for (label, seconds) in split_into_buckets(session.timestamp_start.max(from), session.timestamp_end.min(to), &r#type, &tz, settings.week_start) {
+            *buckets.entry(label).or_default().entry(id).or_default() += seconds;
+        }
+    }
+
+    Ok(json::Json(
+        buckets.into_iter().rev().map(|(date, games)| GameHistory {
+            r#type: r#type.clone(),
+            date,
+            games: games.into_iter().map(|(id, playtime)| HistoryGame { id, playtime }).collect()
+        }).collect()
+    ))
+}
+
+async fn get_session(db: &mut SqliteConnection, id: i64) -> Option<Session> {
+    sqlx::query_as!(
+        Session,
+        "SELECT id, subgame, timestamp_start, timestamp_end, exit_code, launch_profile, manual, signal, terminated, failed,
+                peak_cpu, average_cpu, peak_rss, average_rss, peak_threads, read_bytes, written_bytes
+        FROM history
+        WHERE id = ?",
+        id
+    ).fetch_optional(&mut *db)
+    .await
+    .ok()?
+}
+
+// id 0 adds a manual session, any other id corrects the times / subgame of an existing one
+#[post("/sessions", format = "json", data = "<data>")]
+async fn post_session(mut db: Connection<Db>, settings: &State<Settings>, data: json::Json<Session>) -> Option<json::Json<Session>> {
+    if data.timestamp_end < data.timestamp_start {
+        return None;
+    }
+    if data.id == 0 {
+        let row = sqlx::query!(
+            "INSERT INTO history (subgame, timestamp_start, timestamp_end, exit_code, launch_profile, manual) VALUES (?,?,?,?,?,true); SELECT last_insert_rowid() AS id;",
+            data.subgame,
+            data.timestamp_start,
+            data.timestamp_end,
+            data.exit_code,
+            data.launch_profile
+        ).fetch_optional(&mut **db)
+        .await
+        .ok()??;
+        update_playtime(&mut db, settings, data.subgame).await.ok()?;
+
+        return Some(json::Json(get_session(&mut db, row.id as i64).await?));
+    }
+
+    let previous = sqlx::query!(
+        "SELECT subgame FROM history WHERE id = ?",
+        data.id
+    ).fetch_optional(&mut **db)
+    .await
+    .ok()??;
+    // Corrected times were entered by hand, so the session counts as manual and never as a failed launch
+    sqlx::query!(
+        "UPDATE history SET subgame = ?, timestamp_start = ?, timestamp_end = ?, manual = true, failed = false WHERE id = ?",
+        data.subgame,
+        data.timestamp_start,
+        data.timestamp_end,
+        data.id
+    ).execute(&mut **db)
+    .await
+    .ok()?;
+    update_playtime(&mut db, settings, data.subgame).await.ok()?;
+    if previous.subgame != data.subgame {
+        update_playtime(&mut db, settings, previous.subgame).await.ok()?;
+    }
+
+    Some(json::Json(get_session(&mut db, data.id).await?))
+}
+
+// Downsampled resource usage of a session that was launched through the archive
+#[get("/sessions/resources?<id>")]
+async fn get_session_resources(id: i64, mut db: Connection<Db>) -> Option<json::Json<Vec<ResourceSample>>> {
+    Some(json::Json(
+        sqlx::query_as!(
+            ResourceSample,
+            "SELECT timestamp, cpu, rss, threads, read_bytes, written_bytes FROM resource_samples WHERE session = ? ORDER BY timestamp ASC",
+            id
+        ).fetch_all(&mut **db)
+        .await
+        .ok()?
+    ))
+}
+
+#[delete("/sessions?<id>")]
+async fn delete_session(id: i64, mut db: Connection<Db>, settings: &State<Settings>) -> Option<Status> {
+    let row = sqlx::query!(
+        "DELETE FROM history WHERE id = ? RETURNING subgame",
+        id
+    ).fetch_optional(&mut **db)
+    .await
+    .ok()??;
+    update_playtime(&mut db, settings, row.subgame).await.ok()?;
+
+    Some(Status::Gone)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_history, get_sessions, get_session_resources, post_session, delete_session]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: i64, subgame: i64, timestamp_start: i64, timestamp_end: i64) -> Session {
+        Session {
+            id,
+            subgame,
+            timestamp_start,
+            timestamp_end,
+            exit_code: Some(id),
+            launch_profile: Some(format!("profile {}", id)),
+            manual: false,
+            signal: None,
+            terminated: false,
+            failed: false,
+            peak_cpu: None,
+            average_cpu: None,
+            peak_rss: None,
+            average_rss: None,
+            peak_threads: None,
+            read_bytes: None,
+            written_bytes: None,
+        }
+    }
+
+    fn spans(sessions: &[Session]) -> Vec<(i64, i64, i64, i64)> {
+        sessions.iter().map(|s| (s.id, s.subgame, s.timestamp_start, s.timestamp_end)).collect()
+    }
+
+    #[test]
+    fn merges_sessions_within_the_window() {
+        // Subgame 2 played in between doesn't keep the parts of subgame 1 apart
+        let sessions = vec![session(1, 1, 0, 100), session(2, 2, 120, 150), session(3, 1, 200, 300), session(4, 1, 1000, 1100)];
+        let merged = merge_sessions(sessions, 300);
+        assert_eq!(spans(&merged), vec![(1, 1, 0, 300), (2, 2, 120, 150), (4, 1, 1000, 1100)]);
+        assert_eq!(merged[0].exit_code, Some(3));
+        assert_eq!(merged[0].launch_profile.as_deref(), Some("profile 3"));
+    }
+
+    #[test]
+    fn overlapping_parts_keep_the_later_end() {
+        let merged = merge_sessions(vec![session(1, 1, 0, 500), session(2, 1, 100, 200)], 0);
+        assert_eq!(spans(&merged), vec![(1, 1, 0, 500)]);
+    }
+
+    #[test]
+    fn manual_only_if_every_part_is() {
+        let mut parts = vec![session(1, 1, 0, 100), session(2, 1, 150, 200)];
+        parts[0].manual = true;
+        assert!(!merge_sessions(parts, 60)[0].manual);
+    }
+
+    #[test]
+    fn failed_only_if_every_part_is() {
+        let mut parts = vec![session(1, 1, 0, 5), session(2, 1, 10, 500)];
+        parts[0].failed = true;
+        let merged = merge_sessions(parts, 60);
+        assert!(!merged[0].failed);
+        // Signal and termination describe how the merged session ended
+        let mut parts = vec![session(1, 1, 0, 100), session(2, 1, 110, 200)];
+        parts[0].signal = Some(9);
+        parts[1].terminated = true;
+        let merged = merge_sessions(parts, 60);
+        assert_eq!((merged[0].signal, merged[0].terminated), (None, true));
+    }
+
+    #[test]
+    fn merged_resources_take_peaks_and_sum_io() {
+        let mut parts = vec![session(1, 1, 0, 100), session(2, 1, 110, 200)];
+        parts[0].peak_rss = Some(300);
+        parts[1].peak_rss = Some(200);
+        parts[0].read_bytes = Some(10);
+        parts[1].read_bytes = Some(5);
+        parts[1].written_bytes = Some(7);
+        parts[1].average_cpu = Some(0.5);
+        let merged = &merge_sessions(parts, 60)[0];
+        assert_eq!(merged.peak_rss, Some(300));
+        assert_eq!(merged.peak_threads, None);
+        assert_eq!((merged.read_bytes, merged.written_bytes), (Some(15), Some(7)));
+        assert_eq!(merged.average_cpu, Some(0.5));
+    }
+
+    #[tokio::test]
+    async fn configured_threshold_marks_failed_launches() {
+        let pool = rocket_db_pools::sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1).connect("sqlite::memory:").await.unwrap();
+        crate::run_migrations(&pool).await;
+        sqlx::query(
+            "INSERT INTO games (id, name) VALUES (1, 'a');
+            INSERT INTO subgames (id, name, parent) VALUES (1, 'a1', 1);
+            INSERT INTO history (subgame, timestamp_start, timestamp_end, manual, terminated) VALUES
+                (1, 0, 20, false, false), (1, 100, 200, false, false), (1, 300, 305, false, true), (1, 400, 405, true, false);"
+        ).execute(&pool).await.unwrap();
+        let settings = |count_failed_launches: bool| -> Settings {
+            json::serde_json::from_value(json::serde_json::json!({
+                "failed_launch_threshold": 30,
+                "count_failed_launches": count_failed_launches
+            })).unwrap()
+        };
+        let playtime = || sqlx::query_scalar::<_, i64>("SELECT playtime FROM subgames WHERE id = 1").fetch_one(&pool);
+
+        apply_failed_launch_threshold(&pool, &settings(false)).await.unwrap();
+        let failed: Vec<bool> = sqlx::query_scalar("SELECT failed FROM history ORDER BY id").fetch_all(&pool).await.unwrap();
+        assert_eq!(failed, [true, false, false, false]);
+        assert_eq!(playtime().await.unwrap(), 110);
+
+        apply_failed_launch_threshold(&pool, &settings(true)).await.unwrap();
+        assert_eq!(playtime().await.unwrap(), 130);
+    }
+}
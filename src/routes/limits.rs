@@ -0,0 +1,219 @@
+use rocket::{get, post, delete, routes};
+use rocket::http::Status;
+use rocket::serde::json;
+use chrono::Utc;
+use chrono_tz::Tz;
+use rocket_db_pools::{Connection, sqlx};
+use rocket_db_pools::sqlx::SqliteConnection;
+
+use crate::structures::{Db, HistoryType, PlaytimeLimit, Settings};
+use crate::time_helper::{period_start, time_zone};
+
+// The limit with the least playtime left for a subgame, counting history and a session running since `running_since`.
+// Returns None when no limit applies. Callers have to treat an error as a used up limit.
+pub async fn remaining_playtime(db: &mut SqliteConnection, settings: &Settings, subgame: i64, running_since: Option<i64>) -> Result<Option<(PlaytimeLimit, i64)>, sqlx::Error> {
+    // The configured zone is validated at startup
+    let tz = time_zone(settings, None).unwrap_or(Tz::UTC);
+    let now = Utc::now().timestamp();
+    let limits = sqlx::query_as!(
+        PlaytimeLimit,
+        "SELECT l.id, l.game, l.subgame, l.period, l.seconds, l.terminate
+        FROM limits l
+        WHERE (l.game IS NULL AND l.subgame IS NULL)
+           OR l.subgame = ?1
+           OR l.game = (SELECT parent FROM subgames WHERE id = ?1)",
+        subgame
+    ).fetch_all(&mut *db)
+    .await?;
+
+    let mut tightest: Option<(PlaytimeLimit, i64)> = None;
+    for limit in limits {
+        let kind = if limit.period == "week" { HistoryType::WEEK } else { HistoryType::DAY };
+        let start = period_start(now, &kind, &tz, settings.week_start);
+        let used = sqlx::query!(
+            r#"SELECT COALESCE(SUM(MIN(h.timestamp_end, ?2) - MAX(h.timestamp_start, ?1)), 0) AS "used!: i64"
+            FROM history h
+            JOIN subgames s ON s.id = h.subgame
+            WHERE h.timestamp_end > ?1
+              AND ((?3 IS NULL AND ?4 IS NULL) OR s.parent = ?3 OR h.subgame = ?4)
+              AND (h.failed = false OR ?5)"#,
+            start,
+            now,
+            limit.game,
+            limit.subgame,
+            settings.count_failed_launches
+        ).fetch_one(&mut *db)
+        .await?
+        .used;
+        let running = running_since.map(|since| now - since.max(start)).unwrap_or(0);
+        let remaining = limit.seconds - used - running;
+        if tightest.as_ref().is_none_or(|(_, tightest)| remaining < *tightest) {
+            tightest = Some((limit, remaining));
+        }
+    }
+    Ok(tightest)
+}
+
+#[get("/limits")]
+async fn get_limits(mut db: Connection<Db>) -> Option<json::Json<Vec<PlaytimeLimit>>> {
+    Some(json::Json(
+        sqlx::query_as!(
+            PlaytimeLimit,
+            "SELECT id, game, subgame, period, seconds, terminate FROM limits"
+        ).fetch_all(&mut **db)
+        .await
+        .ok()?
+    ))
+}
+
+// Without `game` and `subgame` the limit applies to all games together
+#[post("/limits", format = "json", data = "<data>")]
+async fn post_limit(mut db: Connection<Db>, data: json::Json<PlaytimeLimit>) -> Option<json::Json<PlaytimeLimit>> {
+    if data.period != "day" && data.period != "week" {
+        return None;
+    }
+    if data.id == 0 {
+        let row = sqlx::query!(
+            "INSERT INTO limits (game, subgame, period, seconds, terminate) VALUES (?,?,?,?,?); SELECT last_insert_rowid() AS id;",
+            data.game,
+            data.subgame,
+            data.period,
+            data.seconds,
+            data.terminate
+        ).fetch_optional(&mut **db)
+        .await
+        .ok()??;
+
+        let mut limit = data.into_inner();
+        limit.id = row.id as i64;
+        return Some(json::Json(limit));
+    }
+    sqlx::query!(
+        "UPDATE limits SET game = ?, subgame = ?, period = ?, seconds = ?, terminate = ? WHERE id = ?",
+        data.game,
+        data.subgame,
+        data.period,
+        data.seconds,
+        data.terminate,
+        data.id
+    ).execute(&mut **db)
+    .await
+    .ok()?;
+
+    Some(data)
+}
+
+#[delete("/limits?<id>")]
+async fn delete_limit(id: i64, mut db: Connection<Db>) -> Option<Status> {
+    sqlx::query!(
+        "DELETE FROM limits WHERE id = ?",
+        id
+    ).execute(&mut **db)
+    .await
+    .ok()?;
+
+    Some(Status::Gone)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_limits, post_limit, delete_limit]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Weekday;
+    use rocket_db_pools::sqlx::sqlite::SqlitePoolOptions;
+    use rocket_db_pools::sqlx::pool::PoolConnection;
+    use rocket_db_pools::sqlx::Sqlite;
+
+    // Subgames 1 and 2 belong to game 1, subgame 3 to game 2
+    async fn test_db() -> PoolConnection<Sqlite> {
+        // One connection, every connection to :memory: has a database of its own
+        let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        crate::run_migrations(&pool).await;
+        sqlx::query(
+            "INSERT INTO games (id, name) VALUES (1, 'a'), (2, 'b');
+            INSERT INTO subgames (id, name, parent) VALUES (1, 'a1', 1), (2, 'a2', 1), (3, 'b1', 2);"
+        ).execute(&pool).await.unwrap();
+        pool.acquire().await.unwrap()
+    }
+
+    fn settings(count_failed_launches: bool) -> Settings {
+        json::serde_json::from_value(json::serde_json::json!({
+            "time_zone": "UTC",
+            "count_failed_launches": count_failed_launches
+        })).unwrap()
+    }
+
+    async fn add_limit(db: &mut SqliteConnection, game: Option<i64>, subgame: Option<i64>, period: &str, seconds: i64) {
+        sqlx::query("INSERT INTO limits (game, subgame, period, seconds) VALUES (?, ?, ?, ?)")
+            .bind(game).bind(subgame).bind(period).bind(seconds)
+            .execute(db).await.unwrap();
+    }
+
+    async fn add_session(db: &mut SqliteConnection, subgame: i64, start: i64, end: i64, failed: bool) {
+        sqlx::query("INSERT INTO history (subgame, timestamp_start, timestamp_end, failed) VALUES (?, ?, ?, ?)")
+            .bind(subgame).bind(start).bind(end).bind(failed)
+            .execute(db).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_limit_applies() {
+        let mut db = test_db().await;
+        add_limit(&mut db, None, Some(3), "day", 60).await;
+        assert!(remaining_playtime(&mut db, &settings(false), 1, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn daily_limit_counts_today_only() {
+        let mut db = test_db().await;
+        let now = Utc::now().timestamp();
+        let day = period_start(now, &HistoryType::DAY, &Tz::UTC, Weekday::Mon);
+        // Kept short, the test may run right after midnight
+        let today = (now - day).min(200);
+        add_limit(&mut db, None, Some(1), "day", 3600).await;
+        add_session(&mut db, 1, day - 5000, day - 4000, false).await;
+        // Only the part after midnight counts
+        add_session(&mut db, 1, day - 1000, day + today, false).await;
+        // Other subgames don't count towards a limit of a subgame
+        add_session(&mut db, 2, day, now, false).await;
+
+        let (limit, remaining) = remaining_playtime(&mut db, &settings(false), 1, None).await.unwrap().unwrap();
+        assert_eq!((limit.period.as_str(), remaining), ("day", 3600 - today));
+        let running = now - (now - 50).max(day);
+        let (_, remaining) = remaining_playtime(&mut db, &settings(false), 1, Some(now - 50)).await.unwrap().unwrap();
+        assert_eq!(remaining, 3600 - today - running);
+    }
+
+    #[tokio::test]
+    async fn tightest_of_game_and_global_limits() {
+        let mut db = test_db().await;
+        let now = Utc::now().timestamp();
+        let week = period_start(now, &HistoryType::WEEK, &Tz::UTC, Weekday::Mon);
+        let this_week = (now - week).min(300);
+        add_limit(&mut db, None, None, "week", 100_000).await;
+        add_limit(&mut db, Some(1), None, "week", 1000).await;
+        add_session(&mut db, 2, week - 600, week + this_week, false).await;
+        add_session(&mut db, 3, week, week + (now - week).min(100), false).await;
+
+        // The game's limit counts all of its subgames, the global one every game
+        let (limit, remaining) = remaining_playtime(&mut db, &settings(false), 1, None).await.unwrap().unwrap();
+        assert_eq!((limit.game, remaining), (Some(1), 1000 - this_week));
+        let (limit, remaining) = remaining_playtime(&mut db, &settings(false), 3, None).await.unwrap().unwrap();
+        assert_eq!((limit.game, remaining), (None, 100_000 - this_week - (now - week).min(100)));
+    }
+
+    #[tokio::test]
+    async fn failed_launches_count_as_configured() {
+        let mut db = test_db().await;
+        let now = Utc::now().timestamp();
+        let start = now - (now - period_start(now, &HistoryType::DAY, &Tz::UTC, Weekday::Mon)).min(10);
+        add_limit(&mut db, None, None, "day", 600).await;
+        add_session(&mut db, 1, start, now, true).await;
+        let (_, remaining) = remaining_playtime(&mut db, &settings(false), 1, None).await.unwrap().unwrap();
+        assert_eq!(remaining, 600);
+        let (_, remaining) = remaining_playtime(&mut db, &settings(true), 1, None).await.unwrap().unwrap();
+        assert_eq!(remaining, 600 - (now - start));
+    }
+}
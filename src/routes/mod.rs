@@ -2,4 +2,10 @@ pub mod media;
 pub mod backend_launch;
 pub mod game_config;
 pub mod game;
+pub mod history;
+pub mod stats;
+pub mod review;
+pub mod export;
+pub mod limits;
+pub mod prefix;
 pub mod embedded_files;
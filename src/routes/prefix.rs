@@ -0,0 +1,203 @@
+use rocket::{get, post, delete, routes, Data, State};
+use rocket::data::ToByteUnit;
+use rocket::http::Status;
+use tokio::io::AsyncReadExt;
+use rocket::serde::json;
+use std::fs;
+use std::path::Path;
+use std::sync::{atomic::Ordering, Arc};
+use rocket_db_pools::{Connection, sqlx};
+
+use crate::prefix_helper::{copy_prefix, import_registry, is_managed, subgame_prefix, templates_dir, wineboot, winetricks};
+use crate::routes::backend_launch::{get_compat_tool, get_game_conf};
+use crate::structures::{Db, GameConfig, GameRuntime};
+
+async fn save_game_conf(db: &mut Connection<Db>, id: i64, game_config: &GameConfig) -> Option<()> {
+    let stringified_json = json::serde_json::to_string_pretty(game_config).ok()?;
+    sqlx::query!(
+        "UPDATE subgames SET launch_config = ? WHERE id = ?",
+        stringified_json,
+        id
+    ).execute(&mut ***db)
+    .await
+    .ok()?;
+    Some(())
+}
+
+// Wine doesn't like two programs setting up the same prefix
+fn is_running(game_runtime: &GameRuntime, id: i64) -> bool {
+    game_runtime.game_running.load(Ordering::SeqCst) && game_runtime.current_game.load(Ordering::Relaxed) as i64 == id
+}
+
+fn valid_template_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+// Verbs and settings like "vcrun2019" or "win10" / "csmt=off", anything starting with "-" would be an option of winetricks
+fn valid_verb(verb: &str) -> bool {
+    !verb.is_empty() && !verb.starts_with('-') && verb.chars().all(|c| c.is_ascii_alphanumeric() || "_=.-".contains(c))
+}
+
+// Creates the managed prefix of a subgame, copied from `template` if given, and points its launch config at it
+#[post("/prefix?<id>&<template>")]
+async fn post_prefix(id: i64, template: Option<String>, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let compat_tool = get_compat_tool(&mut db, id).await?;
+    let mut game_config = get_game_conf(&mut db, id).await?;
+    let prefix = subgame_prefix(id);
+    if prefix.exists() || is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    if let Some(template) = template {
+        if !valid_template_name(&template) || !templates_dir().join(&template).is_dir() {
+            return Some(Status::BadRequest);
+        }
+        println!("Copying prefix template {} for subgame {}", template, id);
+        if let Err(err) = copy_prefix(&templates_dir().join(&template), &prefix).await {
+            println!("ERROR! {}", err);
+            let _ = fs::remove_dir_all(&prefix);
+            return Some(Status::InternalServerError);
+        }
+    } else {
+        fs::create_dir_all(&prefix).ok()?;
+    }
+    game_config.game_prefix = prefix.to_string_lossy().to_string();
+
+    // The launch config only points at the prefix once it is set up, a failed one is removed so it can be retried
+    println!("Setting up prefix {}", game_config.game_prefix);
+    if !wineboot(&compat_tool, &game_config).await {
+        let _ = fs::remove_dir_all(&prefix);
+        return Some(Status::InternalServerError);
+    }
+    if save_game_conf(&mut db, id, &game_config).await.is_none() {
+        let _ = fs::remove_dir_all(&prefix);
+        return Some(Status::InternalServerError);
+    }
+    Some(Status::Created)
+}
+
+// Saves the prefix of a subgame as a template for new ones
+#[post("/prefix/template?<id>&<name>")]
+async fn post_prefix_template(id: i64, name: String, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let game_config = get_game_conf(&mut db, id).await?;
+    let template = templates_dir().join(&name);
+    if !valid_template_name(&name) || !Path::new(&game_config.game_prefix).is_dir() {
+        return Some(Status::BadRequest);
+    }
+    if template.exists() || is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    if let Err(err) = copy_prefix(Path::new(&game_config.game_prefix), &template).await {
+        println!("ERROR! {}", err);
+        let _ = fs::remove_dir_all(&template);
+        return Some(Status::InternalServerError);
+    }
+    Some(Status::Created)
+}
+
+#[get("/prefix/templates")]
+async fn get_prefix_templates() -> json::Json<Vec<String>> {
+    let mut templates: Vec<String> = fs::read_dir(templates_dir()).into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    templates.sort();
+    json::Json(templates)
+}
+
+#[post("/prefix/wineboot?<id>")]
+async fn post_prefix_wineboot(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let compat_tool = get_compat_tool(&mut db, id).await?;
+    let game_config = get_game_conf(&mut db, id).await?;
+    if game_config.game_prefix.is_empty() {
+        return Some(Status::BadRequest);
+    }
+    if is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    Some(if wineboot(&compat_tool, &game_config).await { Status::Ok } else { Status::InternalServerError })
+}
+
+// Installs winetricks verbs ("vcrun2019", "d3dx9", ...) into the prefix
+#[post("/prefix/winetricks?<id>", format="json", data="<verbs>")]
+async fn post_prefix_winetricks(id: i64, verbs: json::Json<Vec<String>>, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let compat_tool = get_compat_tool(&mut db, id).await?;
+    let game_config = get_game_conf(&mut db, id).await?;
+    if game_config.game_prefix.is_empty() || verbs.is_empty() || !verbs.iter().all(|verb| valid_verb(verb)) {
+        return Some(Status::BadRequest);
+    }
+    if is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    println!("Running winetricks {:?} for subgame {}", verbs.0, id);
+    Some(if winetricks(&compat_tool, &game_config, &verbs).await { Status::Ok } else { Status::InternalServerError })
+}
+
+// Imports the .reg file in the body into the prefix, regedit exports are often UTF-16 so it is passed on as is
+#[post("/prefix/registry?<id>", data="<data>")]
+async fn post_prefix_registry(id: i64, data: Data<'_>, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let mut registry: Vec<u8> = Vec::new();
+    data.open(5.mebibytes()).read_to_end(&mut registry).await.ok()?;
+    let compat_tool = get_compat_tool(&mut db, id).await?;
+    let game_config = get_game_conf(&mut db, id).await?;
+    if game_config.game_prefix.is_empty() || registry.is_empty() {
+        return Some(Status::BadRequest);
+    }
+    if is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    Some(if import_registry(&compat_tool, &game_config, &registry).await { Status::Ok } else { Status::InternalServerError })
+}
+
+// Deletes a managed prefix, prefixes set up by hand are left alone
+#[delete("/prefix?<id>")]
+async fn delete_prefix(id: i64, game_runtime: &State<Arc<GameRuntime>>, mut db: Connection<Db>) -> Option<Status> {
+    let mut game_config = get_game_conf(&mut db, id).await?;
+    let prefix = Path::new(&game_config.game_prefix);
+    if game_config.game_prefix.is_empty() || !is_managed(prefix, id) {
+        return Some(Status::BadRequest);
+    }
+    if is_running(game_runtime, id) {
+        return Some(Status::Conflict);
+    }
+    println!("Deleting prefix {}", game_config.game_prefix);
+    fs::remove_dir_all(prefix).ok()?;
+    game_config.game_prefix = String::new();
+    save_game_conf(&mut db, id, &game_config).await?;
+    Some(Status::Gone)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![post_prefix, post_prefix_template, get_prefix_templates, post_prefix_wineboot, post_prefix_winetricks, post_prefix_registry, delete_prefix]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_names_stay_in_the_templates_dir() {
+        assert!(valid_template_name("dxvk-base"));
+        assert!(valid_template_name("Proton 9"));
+        assert!(!valid_template_name(""));
+        assert!(!valid_template_name(".."));
+        assert!(!valid_template_name(".hidden"));
+        assert!(!valid_template_name("../1"));
+        assert!(!valid_template_name("a/b"));
+    }
+
+    #[test]
+    fn winetricks_verbs_are_no_options() {
+        assert!(valid_verb("vcrun2019"));
+        assert!(valid_verb("csmt=off"));
+        assert!(valid_verb("dotnet4.8"));
+        assert!(valid_verb("d3dx9_43"));
+        assert!(!valid_verb(""));
+        assert!(!valid_verb("--self-update"));
+        assert!(!valid_verb("-f"));
+        assert!(!valid_verb("vcrun2019 --force"));
+        assert!(!valid_verb("$(reboot)"));
+        assert!(!valid_verb("../verb"));
+    }
+}
@@ -0,0 +1,236 @@
+use rocket::{get, routes, State};
+use rocket::response::content::RawHtml;
+use rocket::serde::json;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Datelike, NaiveDate};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use rocket_db_pools::{Connection, sqlx};
+
+use crate::structures::{Db, FinishedGame, HistoryType, ReviewGame, ReviewSession, Settings, YearReview};
+use crate::time_helper::{split_at_buckets, start_of_day, time_zone, today};
+
+const TOP_GAMES: usize = 5;
+const LONGEST_SESSIONS: usize = 5;
+
+async fn get_cover(db: &mut Connection<Db>, game: i64) -> Option<String> {
+    let row = sqlx::query!(
+        "SELECT blob, mime_type FROM artworks WHERE type = 'card' AND game = ?",
+        game
+    ).fetch_optional(&mut ***db)
+    .await
+    .ok()??;
+
+    Some(format!("data:{};base64,{}", row.mime_type?, STANDARD.encode(row.blob?)))
+}
+
+async fn build_review(db: &mut Connection<Db>, settings: &Settings, year: i32, tz: &Tz) -> Option<YearReview> {
+    let from = start_of_day(NaiveDate::from_ymd_opt(year, 1, 1)?, tz);
+    let to = start_of_day(NaiveDate::from_ymd_opt(year + 1, 1, 1)?, tz);
+
+    let sessions = sqlx::query!(
+        "SELECT h.subgame, h.timestamp_start, h.timestamp_end, h.manual,
+                s.name, s.parent, g.name AS game_name
+        FROM history h
+        JOIN subgames s ON s.id = h.subgame
+        JOIN games g ON g.id = s.parent
+        WHERE h.timestamp_end > ? AND h.timestamp_start < ? AND (h.failed = false OR ?)
+        ORDER BY h.timestamp_start ASC",
+        from,
+        to,
+        settings.count_failed_launches
+    ).fetch_all(&mut ***db)
+    .await
+    .ok()?;
+
+    let mut months = [0; 12];
+    let mut games: HashMap<i64, ReviewGame> = HashMap::new();
+    let mut longest_sessions = vec![];
+    for session in sessions {
+        let (start, end) = (session.timestamp_start.max(from), session.timestamp_end.min(to));
+        for (month, seconds) in split_at_buckets(start, end, &HistoryType::MONTH, tz, chrono::Weekday::Mon) {
+            months[month.month0() as usize] += seconds;
+        }
+        let game = games.entry(session.parent).or_insert_with(|| ReviewGame {
+            id: session.parent,
+            name: session.game_name.clone(),
+            playtime: 0,
+            sessions: 0,
+            cover: None
+        });
+        game.playtime += end - start;
+        if !session.manual {
+            game.sessions += 1;
+            longest_sessions.push(ReviewSession {
+                subgame: session.subgame,
+                name: session.name,
+                game_name: session.game_name,
+                timestamp_start: session.timestamp_start,
+                timestamp_end: session.timestamp_end
+            });
+        }
+    }
+    let session_count = longest_sessions.len() as i64;
+    longest_sessions.sort_by_key(|s| s.timestamp_start - s.timestamp_end);
+    longest_sessions.truncate(LONGEST_SESSIONS);
+
+    // Games whose first recorded session is in this year
+    let first_played = sqlx::query!(
+        r#"SELECT s.parent AS "id!: i64"
+        FROM history h
+        JOIN subgames s ON s.id = h.subgame
+        WHERE h.manual = false AND (h.failed = false OR ?)
+        GROUP BY s.parent
+        HAVING MIN(h.timestamp_start) >= ? AND MIN(h.timestamp_start) < ?"#,
+        settings.count_failed_launches,
+        from,
+        to
+    ).fetch_all(&mut ***db)
+    .await
+    .ok()?;
+
+    let finished = sqlx::query_as!(
+        FinishedGame,
+        r#"SELECT s.id AS subgame, s.name, g.name AS game_name, s.finished AS "finished!: i64"
+        FROM subgames s
+        JOIN games g ON g.id = s.parent
+        WHERE s.finished >= ? AND s.finished < ?
+        ORDER BY s.finished ASC"#,
+        from,
+        to
+    ).fetch_all(&mut ***db)
+    .await
+    .ok()?;
+
+    let mut ranked: Vec<ReviewGame> = games.into_values().collect();
+    ranked.sort_by_key(|g| -g.playtime);
+    let mut top_games = vec![];
+    let mut new_games = vec![];
+    for (index, mut game) in ranked.into_iter().enumerate() {
+        if first_played.iter().any(|row| row.id == game.id) {
+            new_games.push(game.clone());
+        }
+        if index < TOP_GAMES {
+            game.cover = get_cover(db, game.id).await;
+            top_games.push(game);
+        }
+    }
+
+    Some(YearReview {
+        year,
+        total_playtime: months.iter().sum(),
+        session_count,
+        months,
+        top_games,
+        new_games,
+        longest_sessions,
+        finished,
+    })
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn hours(seconds: i64) -> String {
+    format!("{:.1}h", seconds as f64 / 3600.0)
+}
+
+fn format_date(timestamp: i64, tz: &Tz) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .with_timezone(tz)
+        .format("%d.%m.%Y")
+        .to_string()
+}
+
+// Standalone page without any external resources, so it can be saved and shared as is
+fn render_review(review: &YearReview, tz: &Tz) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let busiest = review.months.iter().copied().max().unwrap_or(0).max(1);
+
+    let months: String = review.months.iter().zip(MONTHS).map(|(seconds, name)| format!(
+        r#"<div class="month"><div class="bar" style="height: {}%" title="{}"></div><span>{}</span></div>"#,
+        seconds * 100 / busiest, hours(*seconds), name
+    )).collect();
+    let top_games: String = review.top_games.iter().map(|game| format!(
+        r#"<div class="game">{}<b>{}</b><span>{} in {} sessions</span></div>"#,
+        game.cover.as_ref().map(|cover| format!(r#"<img src="{}">"#, escape_html(cover))).unwrap_or_default(),
+        escape_html(&game.name), hours(game.playtime), game.sessions
+    )).collect();
+    let new_games: String = review.new_games.iter().map(|game| format!(
+        "<li>{} ({})</li>", escape_html(&game.name), hours(game.playtime)
+    )).collect();
+    let longest_sessions: String = review.longest_sessions.iter().map(|session| format!(
+        "<li>{} - {}: {} on {}</li>",
+        escape_html(&session.game_name), escape_html(&session.name),
+        hours(session.timestamp_end - session.timestamp_start), format_date(session.timestamp_start, tz)
+    )).collect();
+    let finished: String = review.finished.iter().map(|game| format!(
+        "<li>{} - {} on {}</li>",
+        escape_html(&game.game_name), escape_html(&game.name), format_date(game.finished, tz)
+    )).collect();
+
+    format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{year} in Games</title>
+<style>
+  body {{ font-family: sans-serif; background: #161616; color: #eee; max-width: 960px; margin: auto; padding: 2em; }}
+  h1 {{ font-size: 3em; margin-bottom: 0; }}
+  .summary {{ font-size: 1.4em; color: #aaa; }}
+  .months {{ display: flex; align-items: flex-end; height: 200px; gap: 8px; }}
+  .month {{ flex: 1; display: flex; flex-direction: column; justify-content: flex-end; height: 100%; text-align: center; }}
+  .bar {{ background: #6a8dff; border-radius: 4px 4px 0 0; min-height: 2px; }}
+  .games {{ display: flex; gap: 16px; flex-wrap: wrap; }}
+  .game {{ display: flex; flex-direction: column; width: 160px; }}
+  .game img {{ width: 160px; border-radius: 8px; margin-bottom: 8px; }}
+  .game span {{ color: #aaa; }}
+</style>
+</head>
+<body>
+<h1>{year} in Games</h1>
+<p class="summary">{total} played in {sessions} sessions</p>
+<h2>Month by month</h2>
+<div class="months">{months}</div>
+<h2>Most played</h2>
+<div class="games">{top_games}</div>
+<h2>Started this year</h2>
+<ul>{new_games}</ul>
+<h2>Longest sessions</h2>
+<ol>{longest_sessions}</ol>
+<h2>Finished</h2>
+<ul>{finished}</ul>
+</body>
+</html>
+"#,
+        year = review.year,
+        total = hours(review.total_playtime),
+        sessions = review.session_count,
+    )
+}
+
+// `year` defaults to the current one, `tz` to the configured zone
+#[get("/review?<year>&<tz>")]
+async fn get_review(mut db: Connection<Db>, settings: &State<Settings>, year: Option<i32>, tz: Option<&str>) -> Option<json::Json<YearReview>> {
+    let tz = time_zone(settings, tz)?;
+    let year = year.unwrap_or(today(&tz).year());
+    Some(json::Json(build_review(&mut db, settings, year, &tz).await?))
+}
+
+#[get("/review/page?<year>&<tz>")]
+async fn get_review_page(mut db: Connection<Db>, settings: &State<Settings>, year: Option<i32>, tz: Option<&str>) -> Option<RawHtml<String>> {
+    let tz = time_zone(settings, tz)?;
+    let year = year.unwrap_or(today(&tz).year());
+    let review = build_review(&mut db, settings, year, &tz).await?;
+    Some(RawHtml(render_review(&review, &tz)))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_review, get_review_page]
+}
@@ -0,0 +1,200 @@
+use rocket::{get, routes, State};
+use rocket::serde::json;
+use chrono::{Datelike, Days, NaiveDate, Timelike};
+use std::collections::BTreeSet;
+use rocket_db_pools::{Connection, sqlx};
+
+use crate::structures::{Db, GameSummary, Heatmap, HistoryType, Session, Settings, Stats};
+use crate::time_helper::{local_date, split_at_buckets, time_zone, today};
+
+// Returns (current, longest) run of consecutive days in `days`
+fn streaks(days: &BTreeSet<NaiveDate>, today: NaiveDate) -> (i64, i64) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+    for day in days {
+        run = match previous {
+            Some(previous) if previous + Days::new(1) == *day => run + 1,
+            _ => 1
+        };
+        longest = longest.max(run);
+        previous = Some(*day);
+    }
+    let current = match previous {
+        Some(last) if last == today || last + Days::new(1) == today => run,
+        _ => 0
+    };
+    (current, longest)
+}
+
+// Session records (count, average, longest) and most played games cover the sessions between `from` and `to`
+// (unix timestamps, everything by default), streaks and per-game summaries always cover the whole history.
+// Manual sessions only count towards playtime, as they don't say when or how long someone actually played.
+#[get("/stats?<from>&<to>&<limit>&<tz>")]
+async fn get_stats(mut db: Connection<Db>, settings: &State<Settings>, from: Option<i64>, to: Option<i64>, limit: Option<i64>, tz: Option<&str>) -> Option<json::Json<Stats>> {
+    let tz = time_zone(settings, tz)?;
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(i64::MAX);
+    let limit = limit.unwrap_or(10);
+
+    let sessions = sqlx::query_as!(
+        Session,
+        "SELECT id, subgame, timestamp_start, timestamp_end, exit_code, launch_profile, manual, signal, terminated, failed,
+                peak_cpu, average_cpu, peak_rss, average_rss, peak_threads, read_bytes, written_bytes
+        FROM history
+        WHERE manual = false AND (failed = false OR ?)
+        ORDER BY timestamp_start ASC",
+        settings.count_failed_launches
+    ).fetch_all(&mut **db)
+    .await
+    .ok()?;
+
+    let mut days = BTreeSet::new();
+    for session in &sessions {
+        let mut day = local_date(session.timestamp_start, &tz);
+        let last = local_date((session.timestamp_end - 1).max(session.timestamp_start), &tz);
+        while day <= last {
+            days.insert(day);
+            day = day + Days::new(1);
+        }
+    }
+    let (current_streak, longest_streak) = streaks(&days, today(&tz));
+
+    let in_period: Vec<Session> = sessions.into_iter()
+        .filter(|s| s.timestamp_end > from && s.timestamp_start < to)
+        .collect();
+    let session_count = in_period.len() as i64;
+    let average_session = in_period.iter()
+        .map(|s| s.timestamp_end - s.timestamp_start)
+        .sum::<i64>()
+        .checked_div(session_count)
+        .unwrap_or(0);
+    let longest_session = in_period.into_iter().max_by_key(|s| s.timestamp_end - s.timestamp_start);
+
+    let games = sqlx::query_as!(
+        GameSummary,
+        r#"SELECT g.id AS "id!: i64",
+                  g.name AS "name!: String",
+                  COALESCE(SUM(h.timestamp_end - h.timestamp_start), 0) AS "playtime!: i64",
+                  COUNT(IIF(h.manual, NULL, h.id)) AS "sessions!: i64",
+                  MIN(IIF(h.manual, NULL, h.timestamp_start)) AS "first_played: i64",
+                  MAX(IIF(h.manual, NULL, h.timestamp_end)) AS "last_played: i64"
+            FROM games g
+            LEFT JOIN subgames s ON s.parent = g.id
+            LEFT JOIN history h ON h.subgame = s.id AND (h.failed = false OR ?)
+            GROUP BY g.id, g.name
+            ORDER BY g.name"#,
+        settings.count_failed_launches
+    ).fetch_all(&mut **db)
+    .await
+    .ok()?;
+
+    let most_played = sqlx::query_as!(
+        GameSummary,
+        r#"SELECT g.id AS "id!: i64",
+                  g.name AS "name!: String",
+                  SUM(MIN(h.timestamp_end, ?2) - MAX(h.timestamp_start, ?1)) AS "playtime!: i64",
+                  COUNT(IIF(h.manual, NULL, h.id)) AS "sessions!: i64",
+                  MIN(IIF(h.manual, NULL, h.timestamp_start)) AS "first_played: i64",
+                  MAX(IIF(h.manual, NULL, h.timestamp_end)) AS "last_played: i64"
+            FROM history h
+            JOIN subgames s ON s.id = h.subgame
+            JOIN games g ON g.id = s.parent
+            WHERE h.timestamp_end > ?1 AND h.timestamp_start < ?2 AND (h.failed = false OR ?4)
+            GROUP BY g.id, g.name
+            ORDER BY 3 DESC
+            LIMIT ?3"#,
+        from,
+        to,
+        limit,
+        settings.count_failed_launches
+    ).fetch_all(&mut **db)
+    .await
+    .ok()?;
+
+    let never_played_subgames = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM subgames s
+            WHERE NOT EXISTS (SELECT 1 FROM history h WHERE h.subgame = s.id AND h.timestamp_end > h.timestamp_start AND (h.failed = false OR ?))"#,
+        settings.count_failed_launches
+    ).fetch_one(&mut **db)
+    .await
+    .ok()?
+    .count;
+
+    Some(json::Json(Stats {
+        current_streak,
+        longest_streak,
+        longest_session,
+        session_count,
+        average_session,
+        total_playtime: games.iter().map(|g| g.playtime).sum(),
+        never_played_games: games.iter().filter(|g| g.playtime == 0).count() as i64,
+        never_played_subgames,
+        most_played,
+        games,
+    }))
+}
+
+// Seconds played per weekday and hour, optionally limited to a game / subgame and a range of unix timestamps.
+// Sessions are split at the hour boundaries of the `tz` parameter or configured zone.
+#[get("/heatmap?<game>&<subgame>&<from>&<to>&<tz>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_heatmap(mut db: Connection<Db>, settings: &State<Settings>, game: Option<i64>, subgame: Option<i64>, from: Option<i64>, to: Option<i64>, tz: Option<&str>) -> Option<json::Json<Heatmap>> {
+    let tz = time_zone(settings, tz)?;
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(i64::MAX);
+
+    let sessions = sqlx::query!(
+        "SELECT h.timestamp_start, h.timestamp_end
+        FROM history h
+        JOIN subgames s ON s.id = h.subgame
+        WHERE h.manual = false
+          AND (?1 IS NULL OR s.parent = ?1)
+          AND (?2 IS NULL OR h.subgame = ?2)
+          AND h.timestamp_end > ?3 AND h.timestamp_start < ?4
+          AND (h.failed = false OR ?5)",
+        game,
+        subgame,
+        from,
+        to,
+        settings.count_failed_launches
+    ).fetch_all(&mut **db)
+    .await
+    .ok()?;
+
+    let first_weekday = settings.week_start;
+    let mut seconds = [[0; 24]; 7];
+    for session in sessions {
+        let parts = split_at_buckets(session.timestamp_start.max(from), session.timestamp_end.min(to), &HistoryType::HOUR, &tz, first_weekday);
+        for (hour, played) in parts {
+            let row = hour.weekday().days_since(first_weekday) as usize;
+            seconds[row][hour.hour() as usize] += played;
+        }
+    }
+
+    Some(json::Json(Heatmap { first_weekday, seconds }))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![get_stats, get_heatmap]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(days: &[u32]) -> BTreeSet<NaiveDate> {
+        days.iter().map(|day| NaiveDate::from_ymd_opt(2024, 5, *day).unwrap()).collect()
+    }
+
+    #[test]
+    fn streaks_count_consecutive_days() {
+        let played = days(&[1, 2, 3, 5, 6]);
+        let day = |day| NaiveDate::from_ymd_opt(2024, 5, day).unwrap();
+        assert_eq!(streaks(&played, day(6)), (2, 3));
+        // Not played yet today, the streak still runs
+        assert_eq!(streaks(&played, day(7)), (2, 3));
+        assert_eq!(streaks(&played, day(8)), (0, 3));
+        assert_eq!(streaks(&BTreeSet::new(), day(8)), (0, 0));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{env, fs, io, mem};
+use std::path::{Path, PathBuf};
+use dirs::{config_dir, home_dir};
+use nix::libc;
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{getgid, getuid};
+use tokio::process::Command;
+
+use crate::compat_helper::{find_executable, steam_client_path};
+use crate::structures::GameConfig;
+
+// System directories the game needs to run at all, mounted read-only
+const SYSTEM_PATHS: [&str; 8] = ["/usr", "/etc", "/opt", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+
+// Devices for graphics, sound and controllers
+const DEVICE_PATHS: [&str; 3] = ["/dev/dri", "/dev/snd", "/dev/input"];
+
+// Upper directory of the archive's overlay, see `mount_helper`
+const UPPER_DIR: &str = "/tmp/game_archive/upper";
+
+fn overlay_dir() -> PathBuf {
+    config_dir().expect("Error locating config dir!").join("game_archive/mount/overlay")
+}
+
+// Directory of the subgame's game in the archive's overlay, other archived games stay hidden
+fn archive_dir(game_config: &GameConfig) -> Option<PathBuf> {
+    let overlay = overlay_dir();
+    let relative = Path::new(&game_config.working_directory).strip_prefix(&overlay).ok()
+        .or_else(|| Path::new(&game_config.executable).strip_prefix(&overlay).ok())?;
+    Some(PathBuf::from(relative.components().next()?.as_os_str()))
+}
+
+fn bind(arguments: &mut Vec<String>, kind: &str, path: &str) {
+    arguments.extend([kind.to_string(), path.to_string(), path.to_string()]);
+}
+
+// Arguments for bubblewrap, only the system, the subgame's directory in the archive and the overlay's upper directory,
+// the prefix and the allowed paths are visible to the game. $HOME is an empty tmpfs.
+fn bwrap_arguments(game_config: &GameConfig, program: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    for path in SYSTEM_PATHS {
+        bind(&mut arguments, "--ro-bind-try", path);
+    }
+    arguments.extend(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"].map(str::to_string));
+    for path in DEVICE_PATHS {
+        bind(&mut arguments, "--dev-bind-try", path);
+    }
+    bind(&mut arguments, "--ro-bind-try", "/sys");
+    if let Some(home) = home_dir() {
+        arguments.extend(["--tmpfs".to_string(), home.to_string_lossy().to_string()]);
+    }
+    // Display and sound servers. Only their sockets, the rest of the runtime dir has the session bus
+    // and systemd's socket, which would let the game start anything outside the sandbox.
+    bind(&mut arguments, "--ro-bind-try", "/tmp/.X11-unix");
+    if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
+        let runtime_dir = Path::new(&runtime_dir);
+        let wayland = env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".to_string());
+        for socket in [wayland.as_str(), "pulse/native", "pipewire-0"] {
+            bind(&mut arguments, "--ro-bind-try", &runtime_dir.join(socket).to_string_lossy());
+        }
+    }
+
+    // The compat tool (e.g. a Proton install in ~/.steam) and the Steam client have to be readable
+    if let Some(tool_dir) = Path::new(program).parent().filter(|dir| dir.is_absolute()) {
+        bind(&mut arguments, "--ro-bind-try", &tool_dir.to_string_lossy());
+    }
+    if let Some(steam) = steam_client_path() {
+        bind(&mut arguments, "--ro-bind-try", &steam.to_string_lossy());
+    }
+    if let Some(game_dir) = archive_dir(game_config) {
+        bind(&mut arguments, "--bind-try", &overlay_dir().join(&game_dir).to_string_lossy());
+        bind(&mut arguments, "--bind-try", &Path::new(UPPER_DIR).join(&game_dir).to_string_lossy());
+    }
+    if !game_config.game_prefix.is_empty() {
+        bind(&mut arguments, "--bind-try", &game_config.game_prefix);
+    }
+    if !game_config.working_directory.is_empty() {
+        bind(&mut arguments, "--bind-try", &game_config.working_directory);
+        arguments.extend(["--chdir".to_string(), game_config.working_directory.clone()]);
+    }
+    for path in &game_config.sandbox.allowed_paths {
+        bind(&mut arguments, "--bind-try", path);
+    }
+
+    arguments.push("--unshare-all".to_string());
+    if !game_config.block_network {
+        arguments.push("--share-net".to_string());
+    }
+    // No --die-with-parent: it kills the game with the server, a detached game has to outlive it
+    // Without a controlling terminal the game can't push input into the server's terminal (TIOCSTI)
+    arguments.push("--new-session".to_string());
+    arguments.extend(["--".to_string(), program.to_string()]);
+    arguments
+}
+
+// Command that starts `program` the way the subgame's config asks for, None if the sandbox
+// is enabled but bubblewrap is not installed
+pub fn game_command(game_config: &GameConfig, program: &str) -> Option<Command> {
+    if !game_config.sandbox.enabled {
+        return Some(Command::new(program));
+    }
+    let bwrap = find_executable("bwrap")?;
+    println!("Starting game in a sandbox");
+    let mut command = Command::new(bwrap);
+    command.args(bwrap_arguments(game_config, program));
+    Some(command)
+}
+
+fn loopback_up() -> io::Result<()> {
+    unsafe {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut request: libc::ifreq = mem::zeroed();
+        for (target, source) in request.ifr_name.iter_mut().zip(b"lo") {
+            *target = *source as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        let result = libc::ioctl(socket, libc::SIOCSIFFLAGS, &request);
+        let error = io::Error::last_os_error();
+        libc::close(socket);
+        if result < 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Starts the game in a new network namespace that only has loopback. It is created in a new user namespace,
+// so no privileges are needed. The game doesn't start at all if that fails. Sandboxed games get bwrap's
+// --unshare-net instead.
+pub fn isolate_network(command: &mut Command) {
+    let uid_map = format!("{0} {0} 1", getuid());
+    let gid_map = format!("{0} {0} 1", getgid());
+    let setup = move || {
+        unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNET)?;
+        fs::write("/proc/self/setgroups", "deny")?;
+        fs::write("/proc/self/uid_map", &uid_map)?;
+        fs::write("/proc/self/gid_map", &gid_map)?;
+        loopback_up()
+    };
+    unsafe {
+        command.pre_exec(setup);
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use rocket::Shutdown;
+use rocket_db_pools::sqlx::{self, ConnectOptions, SqlitePool, SqliteConnection};
+
+use crate::process_helper::{process_start_time, PASSIVE_PROFILE};
+use crate::routes::backend_launch::{enforce_limits, finish_session, terminate_game};
+use crate::resource_helper::{ResourceSampler, SAMPLE_INTERVAL};
+use crate::routes::history::update_playtime;
+use crate::structures::{GameRuntime, RunningSession, Settings};
+
+// Seconds a shutdown waits for running games to be recorded by their launcher
+const SHUTDOWN_TIMEOUT: u64 = 10;
+
+// Seconds between two heartbeats of a running session, at most this much playtime is lost when the server dies
+pub const HEARTBEAT_INTERVAL: u64 = 30;
+
+// A jump of the wall clock that the monotonic clock didn't make is treated as a suspend (milliseconds)
+const SUSPEND_THRESHOLD: i64 = 5000;
+
+// Splits a running session into the segments the machine was actually awake for.
+// `Instant` uses CLOCK_MONOTONIC, which stands still while the system is suspended, the wall clock doesn't.
+pub struct PlaySegments {
+    segments: Vec<(i64, i64)>,
+    segment_start: i64,
+    last_wall: i64,
+    last_instant: Instant,
+}
+
+impl PlaySegments {
+    // Session started at `start` (unix timestamp), earlier than now when following a game again after a restart
+    pub fn resume(start: i64) -> Self {
+        let now = Utc::now().timestamp_millis();
+        PlaySegments {
+            segments: vec![],
+            segment_start: start * 1000,
+            last_wall: now,
+            last_instant: Instant::now(),
+        }
+    }
+
+    // Call regularly while the game runs, returns the length of a suspend that was detected (milliseconds)
+    pub fn check(&mut self) -> Option<i64> {
+        let wall = Utc::now().timestamp_millis();
+        let awake = self.last_instant.elapsed().as_millis() as i64;
+        let suspended = (wall - self.last_wall) - awake;
+        self.last_instant = Instant::now();
+        let last_wall = std::mem::replace(&mut self.last_wall, wall);
+
+        if suspended < SUSPEND_THRESHOLD {
+            return None;
+        }
+        self.segments.push((self.segment_start, last_wall + awake));
+        self.segment_start = wall;
+        Some(suspended)
+    }
+
+    // Start and end (unix timestamps) of every segment
+    pub fn finish(mut self) -> Vec<(i64, i64)> {
+        self.check();
+        self.segments.push((self.segment_start, self.last_wall));
+        self.segments.into_iter()
+            .map(|(start, end)| (start / 1000, end / 1000))
+            .filter(|(start, end)| end > start)
+            .collect()
+    }
+}
+
+// Saves a session that just started, when that fails it is still recorded once it ends but lost on a restart
+pub async fn persist_session(db: &mut SqliteConnection, subgame: i64, pid: u32, start: i64, launch_profile: &str) -> RunningSession {
+    let process_start = process_start_time(pid);
+    let row = sqlx::query!(
+        "INSERT INTO running_sessions (subgame, pid, process_start, timestamp_start, heartbeat, launch_profile) VALUES (?, ?, ?, ?, ?, ?); SELECT last_insert_rowid() AS id;",
+        subgame,
+        pid,
+        process_start,
+        start,
+        start,
+        launch_profile
+    ).fetch_one(&mut *db)
+    .await;
+    let id = match row {
+        Ok(row) => row.id as i64,
+        Err(err) => {
+            println!("Unable to save the running session: {}", err);
+            0
+        }
+    };
+    RunningSession {
+        id,
+        subgame,
+        pid: pid as i64,
+        process_start,
+        timestamp_start: start,
+        heartbeat: start,
+        launch_profile: launch_profile.to_string(),
+        resources: None,
+    }
+}
+
+pub async fn heartbeat(db: &mut SqliteConnection, session: &RunningSession, resources: &ResourceSampler) {
+    let now = Utc::now().timestamp();
+    let resources = resources.to_json();
+    if let Err(err) = sqlx::query!("UPDATE running_sessions SET heartbeat = ?, resources = ? WHERE id = ?", now, resources, session.id).execute(&mut *db).await {
+        println!("ERROR! {}", err);
+    }
+}
+
+fn is_running(session: &RunningSession) -> bool {
+    session.process_start.is_some() && process_start_time(session.pid as u32) == session.process_start
+}
+
+// Follows a game that was launched before the server restarted until its process is gone.
+// It isn't a child of this server anymore, so its exit status is unknown.
+async fn watch_session(db_pool: SqlitePool, settings: Settings, game_runtime: Arc<GameRuntime>, session: RunningSession, shutdown: Shutdown) {
+    let mut warned = vec![];
+    let mut segments = PlaySegments::resume(session.timestamp_start);
+    let mut resources = ResourceSampler::restore(session.resources.as_deref());
+    let mut limit_check = tokio::time::interval(Duration::from_secs(30));
+    let mut heartbeat_check = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+    let mut process_check = tokio::time::interval(Duration::from_secs(SAMPLE_INTERVAL));
+    let Ok(mut db) = db_pool.acquire().await else {
+        println!("Unable to follow subgame {}!", session.subgame);
+        return;
+    };
+    let mut shutting_down = false;
+    let mut detached = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.clone(), if !shutting_down => {
+                shutting_down = true;
+                if settings.shutdown_action != "terminate" {
+                    detached = true;
+                    break;
+                }
+                terminate_game(&game_runtime);
+            },
+            _ = limit_check.tick() => enforce_limits(&mut db, &settings, &game_runtime, session.subgame, &mut warned).await,
+            _ = heartbeat_check.tick() => heartbeat(&mut db, &session, &resources).await,
+            _ = process_check.tick() => {
+                if !is_running(&session) {
+                    break;
+                }
+                if let Some(suspended) = segments.check() {
+                    game_runtime.running_since.fetch_add((suspended / 1000) as isize, Ordering::Relaxed);
+                }
+                resources.sample(session.pid as u32);
+                *game_runtime.resources.lock().unwrap() = resources.usage();
+            },
+        }
+    }
+    game_runtime.game_running.store(false, Ordering::SeqCst);
+    if detached {
+        heartbeat(&mut db, &session, &resources).await;
+        return;
+    }
+    finish_session(&mut db, &settings, &game_runtime, &session, segments.finish(), None, &resources).await;
+}
+
+// Sessions that were still running when the server stopped: games that are still alive are followed again,
+// the others are closed at their last heartbeat
+pub async fn recover_sessions(db_pool: SqlitePool, settings: Settings, game_runtime: Arc<GameRuntime>, shutdown: Shutdown) {
+    let Ok(mut db) = db_pool.acquire().await else {
+        return;
+    };
+    let Ok(sessions) = sqlx::query_as!(
+        RunningSession,
+        "SELECT id, subgame, pid, process_start, timestamp_start, heartbeat, launch_profile, resources FROM running_sessions"
+    ).fetch_all(&mut *db)
+    .await else {
+        return;
+    };
+
+    for session in sessions {
+        if is_running(&session) && !game_runtime.game_running.swap(true, Ordering::SeqCst) {
+            println!("Subgame {} is still running, following it again", session.subgame);
+            game_runtime.current_game.store(session.subgame as isize, Ordering::Relaxed);
+            game_runtime.running_since.store(session.timestamp_start as isize, Ordering::Relaxed);
+            game_runtime.pid.store(session.pid as u32, Ordering::Relaxed);
+            game_runtime.session_id.store(session.id as isize, Ordering::Relaxed);
+            tokio::spawn(watch_session(db_pool.clone(), settings.clone(), game_runtime.clone(), session, shutdown.clone()));
+        } else {
+            println!("Subgame {} stopped while the server was down, closing its session", session.subgame);
+            let segments = vec![(session.timestamp_start, session.heartbeat)];
+            let resources = ResourceSampler::restore(session.resources.as_deref());
+            finish_session(&mut db, &settings, &game_runtime, &session, segments, None, &resources).await;
+        }
+    }
+}
+
+// Runs on shutdown, after the launcher was told to terminate or let go of the running game.
+// Games that are let go keep their running session, the next start follows them again or closes them.
+// The pool is closed at the same time, so this works on a connection of its own.
+// Returns whether games were left running.
+pub async fn finalize_sessions(db_pool: &SqlitePool, settings: &Settings, game_runtime: &GameRuntime) -> bool {
+    let Ok(mut db) = db_pool.connect_options().connect().await else {
+        println!("Unable to finalize the running sessions!");
+        // Nothing is known about the games, so they are assumed to still run
+        return true;
+    };
+    let terminate = settings.shutdown_action == "terminate";
+    if terminate {
+        for _ in 0..SHUTDOWN_TIMEOUT * 4 {
+            let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!: i64" FROM running_sessions"#).fetch_one(&mut db).await;
+            if remaining.is_ok_and(|row| row.count == 0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+
+    // Games that didn't exit in time
+    let now = Utc::now().timestamp();
+    let sessions = if terminate {
+        sqlx::query_as!(
+            RunningSession,
+            "SELECT id, subgame, pid, process_start, timestamp_start, heartbeat, launch_profile, resources FROM running_sessions"
+        ).fetch_all(&mut db)
+        .await
+        .unwrap_or_default()
+    } else {
+        vec![]
+    };
+    for session in sessions {
+        println!("Closing the session of subgame {}", session.subgame);
+        let resources = ResourceSampler::restore(session.resources.as_deref());
+        finish_session(&mut db, settings, game_runtime, &session, vec![(session.timestamp_start, now)], None, &resources).await;
+    }
+
+    // Passive sessions are in history up to the last scan already, only their playtime is missing
+    let recent = now - settings.passive_tracking_interval as i64 * 2;
+    let passive = sqlx::query!(
+        "SELECT DISTINCT subgame FROM history WHERE launch_profile = ? AND timestamp_end >= ?",
+        PASSIVE_PROFILE,
+        recent
+    ).fetch_all(&mut db)
+    .await
+    .unwrap_or_default();
+    for row in passive {
+        if update_playtime(&mut db, settings, row.subgame).await.is_err() {
+            println!("Failed to update playtime!");
+        }
+    }
+
+    let left_running = sqlx::query!(r#"SELECT COUNT(*) AS "count!: i64" FROM running_sessions"#)
+        .fetch_one(&mut db)
+        .await
+        .map_or(true, |row| row.count > 0);
+
+    if let Err(err) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&mut db).await {
+        println!("Unable to flush the database: {}", err);
+    }
+    left_running
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspend_ends_the_segment() {
+        let now = Utc::now().timestamp_millis();
+        let mut segments = PlaySegments {
+            segments: vec![],
+            segment_start: now - 3_600_000,
+            // The wall clock moved on 10 minutes further than the monotonic one
+            last_wall: now - 600_000,
+            last_instant: Instant::now(),
+        };
+        let suspended = segments.check().unwrap();
+        assert!((600_000..610_000).contains(&suspended));
+        assert_eq!(segments.segments.len(), 1);
+        assert_eq!(segments.segments[0].0, now - 3_600_000);
+        assert!(segments.segments[0].1 < now - 590_000);
+
+        let parts = segments.finish();
+        assert_eq!(parts[0].0, (now - 3_600_000) / 1000);
+        // Nothing of the suspend is counted
+        assert!(parts[1..].iter().all(|(start, _)| *start >= now / 1000));
+    }
+
+    #[test]
+    fn no_suspend_keeps_one_segment() {
+        let mut segments = PlaySegments::resume(Utc::now().timestamp() - 60);
+        assert_eq!(segments.check(), None);
+        let parts = segments.finish();
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].1 - parts[0].0 >= 60);
+    }
+}
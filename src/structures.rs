@@ -1,7 +1,9 @@
 use std::sync::{atomic::AtomicBool, atomic::AtomicIsize, Mutex, atomic::AtomicU32};
 use std::collections::HashMap;
+use chrono::Weekday;
 use rocket::serde::{Deserialize, Serialize};
 use rocket_db_pools::{sqlx, Database};
+use tokio::sync::broadcast;
 
 #[derive(Database)]
 #[database("sqlite_db")]
@@ -11,7 +13,8 @@ pub struct Db(sqlx::SqlitePool);
 pub enum HistoryType {
     MONTH,
     WEEK,
-    DAY
+    DAY,
+    HOUR
 }
 
 #[derive(Deserialize, Serialize)]
@@ -27,11 +30,180 @@ pub struct GameHistory {
     pub games: Vec<HistoryGame>
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub subgame: i64,
+    pub timestamp_start: i64,
+    pub timestamp_end: i64,
+    pub exit_code: Option<i64>,
+    pub launch_profile: Option<String>,
+    // Added or edited by hand instead of being recorded by the launcher
+    #[serde(default)]
+    pub manual: bool,
+    // Signal that killed the game, exit_code is empty then
+    #[serde(default)]
+    pub signal: Option<i64>,
+    // Ended through /api/terminate or a playtime limit
+    #[serde(default)]
+    pub terminated: bool,
+    // Ended within `Settings::failed_launch_threshold` seconds, not counted as playtime by default
+    #[serde(default)]
+    pub failed: bool,
+    // Resource usage of the game's process tree, see /sessions/resources for the whole series
+    #[serde(default)]
+    pub peak_cpu: Option<f64>,
+    #[serde(default)]
+    pub average_cpu: Option<f64>,
+    #[serde(default)]
+    pub peak_rss: Option<i64>,
+    #[serde(default)]
+    pub average_rss: Option<i64>,
+    #[serde(default)]
+    pub peak_threads: Option<i64>,
+    #[serde(default)]
+    pub read_bytes: Option<i64>,
+    #[serde(default)]
+    pub written_bytes: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ResourceSample {
+    pub timestamp: i64,
+    // CPU cores in use, 1.0 is one core fully used
+    pub cpu: f64,
+    // bytes
+    pub rss: i64,
+    pub threads: i64,
+    // bytes since the game started
+    pub read_bytes: i64,
+    pub written_bytes: i64,
+}
+
+// Resource usage of a running game, see `resource_helper`
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ResourceUsage {
+    pub current: Option<ResourceSample>,
+    pub peak_cpu: f64,
+    pub average_cpu: f64,
+    pub peak_rss: i64,
+    pub average_rss: i64,
+    pub peak_threads: i64,
+    pub read_bytes: i64,
+    pub written_bytes: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GameSummary {
+    pub id: i64,
+    pub name: String,
+    // seconds
+    pub playtime: i64,
+    pub sessions: i64,
+    pub first_played: Option<i64>,
+    pub last_played: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Stats {
+    // days, the current streak is still running if nothing has been played today yet
+    pub current_streak: i64,
+    pub longest_streak: i64,
+    pub longest_session: Option<Session>,
+    pub session_count: i64,
+    // seconds
+    pub average_session: i64,
+    pub total_playtime: i64,
+    pub never_played_games: i64,
+    pub never_played_subgames: i64,
+    pub most_played: Vec<GameSummary>,
+    pub games: Vec<GameSummary>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Heatmap {
+    // Weekday of the first row, rows follow in order
+    pub first_weekday: Weekday,
+    // seconds played per weekday and hour of the day
+    pub seconds: [[i64; 24]; 7],
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ReviewGame {
+    pub id: i64,
+    pub name: String,
+    // seconds
+    pub playtime: i64,
+    pub sessions: i64,
+    // data: URI of the game's card
+    pub cover: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReviewSession {
+    pub subgame: i64,
+    pub name: String,
+    pub game_name: String,
+    pub timestamp_start: i64,
+    pub timestamp_end: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FinishedGame {
+    pub subgame: i64,
+    pub name: String,
+    pub game_name: String,
+    // unix timestamp
+    pub finished: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct YearReview {
+    pub year: i32,
+    // seconds
+    pub total_playtime: i64,
+    pub session_count: i64,
+    // seconds played per month, January first
+    pub months: [i64; 12],
+    pub top_games: Vec<ReviewGame>,
+    // games that were played for the first time this year
+    pub new_games: Vec<ReviewGame>,
+    pub longest_sessions: Vec<ReviewSession>,
+    pub finished: Vec<FinishedGame>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ExportSession {
+    pub id: i64,
+    pub game: String,
+    pub subgame: String,
+    // RFC 3339
+    pub start: String,
+    pub end: String,
+    // seconds
+    pub duration: i64,
+    pub exit_code: Option<i64>,
+    pub manual: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PlaytimeLimit {
+    pub id: i64,
+    pub game: Option<i64>,
+    pub subgame: Option<i64>,
+    // "day" or "week"
+    pub period: String,
+    pub seconds: i64,
+    // end the running game once the limit is used up
+    pub terminate: bool,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct MetaGame {
     pub id: Option<i64>,
     pub name: Option<String>,
-    pub playtime: Option<f64>,
+    // seconds
+    pub playtime: Option<i64>,
     pub last_launch: Option<i64>,
     pub is_archived: Option<bool>,
 }
@@ -47,10 +219,19 @@ pub struct Game {
 pub struct SubGame {
     pub id: i64,
     pub name: String,
-    pub playtime: Option<f64>,
+    // seconds
+    pub playtime: Option<i64>,
     pub last_launch: Option<i64>,
     pub is_archived: bool,
-    pub parent: i64
+    pub parent: i64,
+    // unix timestamp, set through /subgame_finished
+    #[serde(default)]
+    pub finished: Option<i64>,
+    // Failed launches and sessions that ended with an error / signal without being terminated
+    #[serde(default)]
+    pub crash_count: i64,
+    #[serde(default)]
+    pub last_launch_failed: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -59,6 +240,27 @@ pub struct CompatTool {
    pub name:String,
    pub executable: String,
    pub environment: HashMap<String,String>,
+   // "proton" (the proton script), "umu" (umu-run) or "wine", see `compat_helper`
+   #[serde(default = "default_compat_kind")]
+   pub kind: String,
+   // Registered Proton tool an umu tool passes as PROTONPATH, the latest UMU-Proton if None
+   #[serde(default)]
+   pub proton_tool: Option<i64>,
+}
+
+// A compat tool found by /compat_tools/discover
+#[derive(Deserialize, Serialize)]
+pub struct CompatToolCandidate {
+    // Set once it is in `compat_tools`
+    pub id: Option<i64>,
+    pub name: String,
+    pub executable: String,
+    pub kind: String,
+    pub version: Option<String>,
+}
+
+fn default_compat_kind() -> String {
+    crate::compat_helper::PROTON.to_string()
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -69,6 +271,58 @@ pub struct GameConfig {
     pub executable: String,
     pub environment: HashMap<String,String>,
     pub archive_file: String,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    // Start the game without network access (only loopback), with or without the sandbox
+    #[serde(default)]
+    pub block_network: bool,
+    // umu-database id ("umu-1091500") that selects the protonfixes, "umu-default" if empty
+    #[serde(default)]
+    pub game_id: String,
+    // Store the game was bought in ("gog", "egs", ...), protonfixes are looked up by store id then
+    #[serde(default)]
+    pub store: String,
+}
+
+// Filesystem isolation of a subgame, see `sandbox_helper`
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    // Further paths the game may read and write
+    #[serde(default)]
+    pub allowed_paths: Vec<String>,
+}
+
+// Limits of a subgame's processes, see `cgroup_helper`
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ResourceLimits {
+    // Bytes
+    pub memory_max: Option<u64>,
+    // 1 - 10000, other processes have 100
+    pub cpu_weight: Option<u64>,
+    // Percent of one CPU, 200 allows two full cores
+    pub cpu_quota: Option<u64>,
+    // CPUs the game may run on, all if empty
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+    pub nice: Option<i32>,
+    pub open_files: Option<u64>,
+}
+
+// A game launched through /api/launch that is still running, see `session_helper`
+pub struct RunningSession {
+    pub id: i64,
+    pub subgame: i64,
+    pub pid: i64,
+    // Clock ticks after boot, tells the game apart from a later process with the same pid
+    pub process_start: Option<i64>,
+    pub timestamp_start: i64,
+    pub heartbeat: i64,
+    pub launch_profile: String,
+    // `ResourceSampler` as of the last heartbeat
+    pub resources: Option<String>,
 }
 
 pub struct GameRuntime {
@@ -76,5 +330,96 @@ pub struct GameRuntime {
     pub game_running: AtomicBool,
     pub running_since: AtomicIsize,
     pub pid: AtomicU32, 
+    pub limit_warning: Mutex<Option<String>>,
+    // Set when the running game is ended through `terminate_game`
+    pub terminate_requested: AtomicBool,
+    // id of the game's row in `running_sessions`
+    pub session_id: AtomicIsize,
+    pub events: broadcast::Sender<RuntimeEvent>,
+    pub resources: Mutex<Option<ResourceUsage>>,
+}
+
+// Answer of /api/status and first event of /api/events
+#[derive(Serialize)]
+pub struct RuntimeStatus {
+    pub current_game: i64,
+    pub game_running: bool,
+    pub running_since: i64,
+    pub limit_warning: Option<String>,
+    pub session_id: Option<i64>,
+    pub subgame_name: Option<String>,
+    pub pid: Option<u32>,
+    // seconds played so far, without suspends
+    pub elapsed: i64,
+    // mount points of the archive
+    pub mounts: Vec<String>,
+    pub resources: Option<ResourceUsage>,
+}
+
+// Pushed to /api/events as it happens, `type` is also the name of the SSE event
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuntimeEvent {
+    Start { session_id: i64, subgame: i64, pid: u32 },
+    Stop { session_id: i64, subgame: i64, seconds: i64, exit_code: Option<i64>, terminated: bool },
+    // Failed launches and games that ended with an error / signal without being terminated
+    Crash { session_id: i64, subgame: i64, seconds: i64, exit_code: Option<i64>, signal: Option<i64>, failed: bool },
+    LimitWarning { message: String },
+}
+
+
+// Custom keys read from the `[default]` table of Rocket.toml
+#[derive(Deserialize, Clone)]
+pub struct Settings {
+    // Sessions of the same subgame that are less than this many seconds apart are shown as one
+    #[serde(default = "default_merge_window")]
+    pub merge_window: i64,
+    // IANA name (e.g. "Europe/Berlin") used to split history into days, the system zone if not set
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    // "Monday" or "Sunday"
+    #[serde(default = "default_week_start")]
+    pub week_start: Weekday,
+    // Seconds of playtime left at which a limit warning is shown
+    #[serde(default = "default_limit_warnings")]
+    pub limit_warnings: Vec<i64>,
+    // Sessions shorter than this many seconds are recorded as failed launches
+    #[serde(default = "default_failed_launch_threshold")]
+    pub failed_launch_threshold: i64,
+    // Whether failed launches count towards playtime, history and stats
+    #[serde(default)]
+    pub count_failed_launches: bool,
+    // Record sessions of games that were started outside the archive (e.g. from Steam or a terminal)
+    #[serde(default)]
+    pub passive_tracking: bool,
+    // Seconds between two scans for such games
+    #[serde(default = "default_passive_tracking_interval")]
+    pub passive_tracking_interval: u64,
+    // What happens to a running game when the server shuts down: "terminate" or "detach" (keep it running)
+    #[serde(default = "default_shutdown_action")]
+    pub shutdown_action: String,
+}
+
+fn default_merge_window() -> i64 {
+    600
 }
 
+fn default_week_start() -> Weekday {
+    Weekday::Mon
+}
+
+fn default_limit_warnings() -> Vec<i64> {
+    vec![900, 300]
+}
+
+fn default_failed_launch_threshold() -> i64 {
+    15
+}
+
+fn default_passive_tracking_interval() -> u64 {
+    10
+}
+
+fn default_shutdown_action() -> String {
+    "detach".to_string()
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Datelike, Days, LocalResult, Months, NaiveDate, NaiveDateTime, TimeZone, Timelike, Weekday};
+use chrono_tz::Tz;
+
+use crate::structures::{HistoryType, Settings};
+
+// Zone used for bucketing: the `tz` parameter of a request, then the configured `time_zone`,
+// then the zone of the system. Returns None for unknown zone names.
+pub fn time_zone(settings: &Settings, requested: Option<&str>) -> Option<Tz> {
+    if let Some(name) = requested.or(settings.time_zone.as_deref()) {
+        return name.parse::<Tz>().ok();
+    }
+    Some(
+        iana_time_zone::get_timezone()
+            .ok()
+            .and_then(|name| name.parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC)
+    )
+}
+
+fn to_local(timestamp: i64, tz: &Tz) -> NaiveDateTime {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default().with_timezone(tz).naive_local()
+}
+
+// Unix timestamp of a local time, times skipped by a DST change resolve to the end of the gap
+fn to_timestamp(time: NaiveDateTime, tz: &Tz) -> i64 {
+    match tz.from_local_datetime(&time) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => t.timestamp(),
+        LocalResult::None => to_timestamp(time + chrono::Duration::minutes(15), tz),
+    }
+}
+
+pub fn start_of_day(date: NaiveDate, tz: &Tz) -> i64 {
+    to_timestamp(date.and_time(Default::default()), tz)
+}
+
+pub fn today(tz: &Tz) -> NaiveDate {
+    chrono::Utc::now().with_timezone(tz).date_naive()
+}
+
+// Start of the day / week / ... that contains `timestamp`
+pub fn period_start(timestamp: i64, kind: &HistoryType, tz: &Tz, week_start: Weekday) -> i64 {
+    to_timestamp(bucket_start(to_local(timestamp, tz), kind, week_start), tz)
+}
+
+pub fn local_date(timestamp: i64, tz: &Tz) -> NaiveDate {
+    to_local(timestamp, tz).date()
+}
+
+// First second of the bucket that contains `time`
+fn bucket_start(time: NaiveDateTime, kind: &HistoryType, week_start: Weekday) -> NaiveDateTime {
+    let date = time.date();
+    match kind {
+        HistoryType::HOUR => date.and_hms_opt(time.hour(), 0, 0).unwrap(),
+        HistoryType::DAY => date.and_time(Default::default()),
+        HistoryType::WEEK => date.week(week_start).first_day().and_time(Default::default()),
+        HistoryType::MONTH => date.with_day(1).unwrap().and_time(Default::default()),
+    }
+}
+
+fn next_bucket(start: NaiveDateTime, kind: &HistoryType) -> NaiveDateTime {
+    match kind {
+        HistoryType::HOUR => start + chrono::Duration::hours(1),
+        HistoryType::DAY => start + Days::new(1),
+        HistoryType::WEEK => start + Days::new(7),
+        HistoryType::MONTH => start + Months::new(1),
+    }
+}
+
+// Labels sort chronologically as strings
+fn bucket_label(start: NaiveDateTime, kind: &HistoryType, week_start: Weekday) -> String {
+    match kind {
+        HistoryType::HOUR => start.format("%Y-%m-%d %H:00"),
+        HistoryType::DAY => start.format("%Y-%m-%d"),
+        HistoryType::WEEK if week_start == Weekday::Sun => start.format("%Y-%U"),
+        HistoryType::WEEK => start.format("%Y-%W"),
+        HistoryType::MONTH => start.format("%Y-%m"),
+    }.to_string()
+}
+
+// Split the interval [start, end) at the bucket boundaries of the given zone,
+// returns the local start and seconds played per bucket
+pub fn split_at_buckets(start: i64, end: i64, kind: &HistoryType, tz: &Tz, week_start: Weekday) -> Vec<(NaiveDateTime, i64)> {
+    let mut parts = vec![];
+    let mut current = start;
+    while current < end {
+        let bucket = bucket_start(to_local(current, tz), kind, week_start);
+        let next = to_timestamp(next_bucket(bucket, kind), tz).min(end);
+        if next <= current {
+            break;
+        }
+        parts.push((bucket, next - current));
+        current = next;
+    }
+    parts
+}
+
+// Same as `split_at_buckets`, but with the label of each bucket
+pub fn split_into_buckets(start: i64, end: i64, kind: &HistoryType, tz: &Tz, week_start: Weekday) -> Vec<(String, i64)> {
+    split_at_buckets(start, end, kind, tz, week_start)
+        .into_iter()
+        .map(|(bucket, seconds)| (bucket_label(bucket, kind, week_start), seconds))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    fn berlin(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> i64 {
+        Berlin.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap().timestamp()
+    }
+
+    fn local(year: i32, month: u32, day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap().and_time(Default::default())
+    }
+
+    #[test]
+    fn dst_days_have_23_and_25_hours() {
+        let spring = split_at_buckets(berlin(2024, 3, 31, 0, 0), berlin(2024, 4, 1, 0, 0), &HistoryType::DAY, &Berlin, Weekday::Mon);
+        assert_eq!(spring, vec![(local(2024, 3, 31), 23 * 3600)]);
+        let autumn = split_at_buckets(berlin(2024, 10, 27, 0, 0), berlin(2024, 10, 28, 0, 0), &HistoryType::DAY, &Berlin, Weekday::Mon);
+        assert_eq!(autumn, vec![(local(2024, 10, 27), 25 * 3600)]);
+
+        // 02:00 - 03:00 doesn't exist on the spring day
+        let hours = split_at_buckets(berlin(2024, 3, 31, 1, 30), berlin(2024, 3, 31, 3, 30), &HistoryType::HOUR, &Berlin, Weekday::Mon);
+        assert_eq!(hours, vec![
+            (local(2024, 3, 31) + chrono::Duration::hours(1), 1800),
+            (local(2024, 3, 31) + chrono::Duration::hours(3), 1800),
+        ]);
+    }
+
+    #[test]
+    fn session_over_midnight_is_split() {
+        let parts = split_at_buckets(berlin(2024, 5, 10, 23, 30), berlin(2024, 5, 11, 0, 45), &HistoryType::DAY, &Berlin, Weekday::Mon);
+        assert_eq!(parts, vec![(local(2024, 5, 10), 1800), (local(2024, 5, 11), 2700)]);
+    }
+
+    #[test]
+    fn weeks_start_on_the_configured_day() {
+        // Sunday 23:00 until Monday 01:00
+        let (start, end) = (berlin(2024, 5, 12, 23, 0), berlin(2024, 5, 13, 1, 0));
+        let monday = split_at_buckets(start, end, &HistoryType::WEEK, &Berlin, Weekday::Mon);
+        assert_eq!(monday, vec![(local(2024, 5, 6), 3600), (local(2024, 5, 13), 3600)]);
+        let sunday = split_at_buckets(start, end, &HistoryType::WEEK, &Berlin, Weekday::Sun);
+        assert_eq!(sunday, vec![(local(2024, 5, 12), 7200)]);
+
+        assert_eq!(split_into_buckets(start, end, &HistoryType::WEEK, &Berlin, Weekday::Sun), vec![("2024-19".to_string(), 7200)]);
+    }
+}
@@ -71,7 +71,7 @@ function parseSubgame(subgame_el, gameID, last_launch, is_archived){
   return {
     id: parseInt(subgame_el.querySelector("[name='subid']").content),
     name: subgame_el.querySelector("[name='subgame_name']").value,
-    playtime: subgame_playtime === 0 ? null : subgame_playtime,
+    playtime: subgame_playtime === 0 ? null : Math.round(subgame_playtime * 3600), // hours -> seconds
     last_launch: last_launch,
     is_archived: is_archived,
     parent: gameID
@@ -85,7 +85,9 @@ function parseGameConf(subgame_el, archive_file) {
     game_prefix: subgame_el.querySelector("[name='subgame_winprefix']").value,
     executable: subgame_el.querySelector("[name='subgame_executable']").value,
     environment: toHashMap(subgame_el.querySelector("[name='subgame_env']").value),
-    archive_file: archive_file
+    archive_file: archive_file,
+    game_id: subgame_el.querySelector("[name='subgame_gameid']").value,
+    store: subgame_el.querySelector("[name='subgame_store']").value
   }
 }
 
@@ -131,6 +133,11 @@ function formatDate(date, locale){
   return "Never"
 }
 
+function formatHours(seconds) {
+  // The API counts playtime in seconds
+  return ((seconds ?? 0) / 3600).toFixed(2);
+}
+
 function timeSince(unixTimestamp) {
   // Convert timestamp (seconds) → milliseconds
   const past = unixTimestamp * 1000;